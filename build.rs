@@ -1,5 +1,9 @@
+use std::{env, path::PathBuf};
+
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("externalscaler_descriptor.bin"))
         .compile(&["proto/externalscaler.proto"], &["proto"])
         .unwrap();
 }
@@ -1,15 +1,106 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use color_eyre::Result;
+use rand::Rng;
+use reqwest::{header::HeaderMap, StatusCode};
 use serde::{Deserialize, Serialize};
-use tracing::instrument;
+use thiserror::Error;
+
+use crate::{health::FetchReporter, queue::QueueSelector, telemetry::Telemetry};
+use tokio::time::Instant;
+use tracing::{field, instrument, warn, Span};
+
+/// Errors returned by the agent API client.
+///
+/// Errors are cloneable so that a failed fetch can be returned to every caller
+/// waiting for it.
+#[derive(Debug, Clone, Error)]
+pub enum AgentApiError {
+    #[error("failed to send request to buildkite: {0}")]
+    Transport(#[source] Arc<reqwest::Error>),
+    #[error("request to buildkite timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("agent token rejected by buildkite ({status}){}", format_message(.message))]
+    Unauthorized {
+        status: StatusCode,
+        message: Option<String>,
+    },
+    #[error("buildkite metrics endpoint not found{}", format_message(.message))]
+    NotFound { message: Option<String> },
+    #[error("rate limited by buildkite, retry after {}s", retry_after.as_secs_f64().ceil())]
+    RateLimited { retry_after: Duration },
+    #[error("buildkite server error ({status}){}", format_message(.message))]
+    Server {
+        status: StatusCode,
+        message: Option<String>,
+    },
+    #[error("unexpected response from buildkite ({status}){}", format_message(.message))]
+    UnexpectedStatus {
+        status: StatusCode,
+        message: Option<String>,
+    },
+    #[error("failed to decode buildkite response: {0}")]
+    Decode(#[source] Arc<reqwest::Error>),
+    #[error("invalid http client configuration: {0}")]
+    Config(#[source] Arc<reqwest::Error>),
+    #[error("metrics have not been fetched yet")]
+    NotFetched,
+    #[error("metrics are stale ({age:?} old)")]
+    Stale { age: Duration },
+}
+
+pub type Result<T, E = AgentApiError> = std::result::Result<T, E>;
+
+/// A source of Buildkite metrics.
+#[tonic::async_trait]
+pub trait MetricsProvider: Send + Sync {
+    /// Returns the latest metrics.
+    async fn metrics(&self) -> Result<Arc<Metrics>>;
+}
 
 /// Buildkite metrics API client.
-#[derive(Debug)]
 pub struct BuildkiteMetrics {
     client: reqwest::Client,
     base_url: String,
     token: Option<String>,
+    retry_policy: RetryPolicy,
+    request_timeout: Duration,
+    rate_limited_until: Mutex<Option<Instant>>,
+    telemetry: Option<Telemetry>,
+    health: Option<FetchReporter>,
+}
+
+/// Builder for [BuildkiteMetrics].
+#[derive(Clone)]
+pub struct BuildkiteMetricsBuilder {
+    base_url: String,
+    token: Option<String>,
+    retry_policy: RetryPolicy,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+    root_certificates: Vec<reqwest::Certificate>,
+    user_agent: String,
+    telemetry: Option<Telemetry>,
+    health: Option<FetchReporter>,
+}
+
+/// How failed requests to the Buildkite API are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled after every retry.
+    pub initial_backoff: Duration,
+    /// Upper bound on the backoff between retries.
+    pub max_backoff: Duration,
+    /// Overall time budget for a request, including all retries.
+    pub deadline: Duration,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,6 +111,14 @@ pub struct JobQueue {
     pub total: i64,
 }
 
+/// A job state counted in [JobQueue].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum JobState {
+    Scheduled,
+    Waiting,
+    Running,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JobMetrics {
     pub scheduled: i64,
@@ -56,29 +155,455 @@ pub struct Metrics {
     pub organization: Organization,
 }
 
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default user agent, including the scaler version.
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
 impl BuildkiteMetrics {
+    /// Creates a client with the default configuration.
     pub fn new(base_url: impl Into<String>, token: impl Into<Option<String>>) -> Self {
-        Self {
-            client: reqwest::Client::new(),
+        Self::builder(base_url)
+            .token(token)
+            .build()
+            .expect("default http client configuration")
+    }
+
+    /// Returns a builder to configure the client.
+    pub fn builder(base_url: impl Into<String>) -> BuildkiteMetricsBuilder {
+        BuildkiteMetricsBuilder {
             base_url: base_url.into(),
-            token: token.into(),
+            token: None,
+            retry_policy: RetryPolicy::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            proxy: None,
+            no_proxy: None,
+            root_certificates: Vec::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            telemetry: None,
+            health: None,
         }
     }
 
     /// Get metrics from the Buildkite API.
-    #[instrument(skip(self), err(Debug))]
+    ///
+    /// Transient failures are retried according to the client's [RetryPolicy].
+    /// While Buildkite is rate limiting the client, requests fail immediately
+    /// with [AgentApiError::RateLimited].
+    #[instrument(skip(self), fields(retries = field::Empty), err(Debug))]
     pub async fn get(&self) -> Result<Metrics> {
+        if let Some(retry_after) = self.rate_limited() {
+            return Err(AgentApiError::RateLimited { retry_after });
+        }
+
+        let deadline = Instant::now() + self.retry_policy.deadline;
+        let mut retries = 0;
+
+        let result = loop {
+            let attempt = tokio::time::timeout_at(deadline, self.fetch())
+                .await
+                .unwrap_or(Err(AgentApiError::Timeout(self.retry_policy.deadline)));
+
+            let err = match attempt {
+                Ok(metrics) => break Ok(metrics),
+                Err(err) => err,
+            };
+
+            if !err.is_transient() || retries >= self.retry_policy.max_retries {
+                break Err(err);
+            }
+
+            let backoff = self.retry_policy.backoff(retries);
+            if Instant::now() + backoff >= deadline {
+                break Err(err);
+            }
+
+            warn!(err = ?err, backoff = ?backoff, "retrying metrics request");
+            tokio::time::sleep(backoff).await;
+            retries += 1;
+        };
+
+        Span::current().record("retries", retries);
+
+        if let Some(health) = &self.health {
+            match &result {
+                Ok(_) => health.record_success(),
+                Err(err) => health.record_error(err),
+            }
+        }
+
+        result
+    }
+
+    async fn fetch(&self) -> Result<Metrics> {
         let url = format!("{}/v3/metrics", self.base_url);
+        let started_at = Instant::now();
         let response = self
             .client
             .get(url)
             .authorization(&self.token)
             .send()
-            .await?;
+            .await
+            .map_err(|err| {
+                if err.is_timeout() {
+                    self.observe_request("timeout", started_at);
+                    AgentApiError::Timeout(self.request_timeout)
+                } else {
+                    self.observe_request("error", started_at);
+                    AgentApiError::Transport(Arc::new(err))
+                }
+            })?;
+
+        let status = response.status();
+        self.observe_request(status.as_str(), started_at);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after =
+                parse_retry_after(response.headers()).unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF);
+            self.set_rate_limited(retry_after);
+            return Err(AgentApiError::RateLimited { retry_after });
+        }
+
+        if let Some(retry_after) = parse_rate_limit_exhausted(response.headers()) {
+            self.set_rate_limited(retry_after);
+        }
+
+        if !status.is_success() {
+            let message = error_message(response).await;
+            warn!(
+                status = %status,
+                message = message.as_deref().unwrap_or_default(),
+                "buildkite returned an error"
+            );
+
+            let err = match status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    AgentApiError::Unauthorized { status, message }
+                }
+                StatusCode::NOT_FOUND => AgentApiError::NotFound { message },
+                status if status.is_server_error() => AgentApiError::Server { status, message },
+                status => AgentApiError::UnexpectedStatus { status, message },
+            };
+            return Err(err);
+        }
+
+        let metrics = response
+            .json::<Metrics>()
+            .await
+            .map_err(|err| AgentApiError::Decode(Arc::new(err)))?;
+
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.observe_metrics(&metrics);
+        }
 
-        let metrics = response.json::<Metrics>().await?;
         Ok(metrics)
     }
+
+    fn observe_request(&self, status: &str, started_at: Instant) {
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.observe_api_request(status, started_at.elapsed());
+        }
+    }
+
+    /// Returns how long the active rate limit lasts, if any.
+    fn rate_limited(&self) -> Option<Duration> {
+        let mut rate_limited_until = self.rate_limited_until.lock().expect("rate limit lock");
+        let retry_after = rate_limited_until.and_then(|until| {
+            let now = Instant::now();
+            (until > now).then(|| until - now)
+        });
+
+        if retry_after.is_none() {
+            *rate_limited_until = None;
+        }
+
+        retry_after
+    }
+
+    /// Stops sending requests for `retry_after`.
+    fn set_rate_limited(&self, retry_after: Duration) {
+        warn!(retry_after = ?retry_after, "buildkite api is rate limiting requests");
+        let until = Instant::now() + retry_after;
+        let mut rate_limited_until = self.rate_limited_until.lock().expect("rate limit lock");
+        *rate_limited_until = Some(rate_limited_until.map_or(until, |current| current.max(until)));
+    }
+}
+
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_ERROR_MESSAGE_LEN: usize = 256;
+
+impl BuildkiteMetricsBuilder {
+    /// Sets the agent API URL.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Sets the agent token used to authenticate requests.
+    pub fn token(mut self, token: impl Into<Option<String>>) -> Self {
+        self.token = token.into();
+        self
+    }
+
+    /// Sets how failed requests are retried.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the timeout to establish a connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout of a single request attempt.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Sends HTTP and HTTPS requests through the given proxy.
+    ///
+    /// Without an explicit proxy, the client uses the `HTTP_PROXY`,
+    /// `HTTPS_PROXY` and `NO_PROXY` environment variables.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Sets the comma-separated list of hosts that bypass the proxy, or the
+    /// `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` proxies if no proxy is set.
+    pub fn no_proxy(mut self, no_proxy: impl Into<String>) -> Self {
+        self.no_proxy = Some(no_proxy.into());
+        self
+    }
+
+    /// Trusts the given root certificate in addition to the built-in ones.
+    pub fn add_root_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Sets the user agent sent with every request.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Records requests and fetched queue metrics in the given telemetry.
+    pub fn telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    /// Records the outcome of every fetch, used for readiness.
+    pub fn health(mut self, reporter: FetchReporter) -> Self {
+        self.health = Some(reporter);
+        self
+    }
+
+    pub fn build(self) -> Result<BuildkiteMetrics> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .user_agent(self.user_agent);
+
+        let no_proxy = self
+            .no_proxy
+            .as_deref()
+            .and_then(reqwest::NoProxy::from_string);
+        if let Some(proxy) = self.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|err| AgentApiError::Config(Arc::new(err)))?
+                .no_proxy(no_proxy);
+            builder = builder.proxy(proxy);
+        } else if no_proxy.is_some() {
+            // Setting a proxy disables the environment proxies, so they are
+            // added back with the hosts that bypass them.
+            for proxy in env_proxies()? {
+                builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+            }
+        }
+
+        for certificate in self.root_certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|err| AgentApiError::Config(Arc::new(err)))?;
+
+        Ok(BuildkiteMetrics {
+            client,
+            base_url: self.base_url,
+            token: self.token,
+            retry_policy: self.retry_policy,
+            request_timeout: self.request_timeout,
+            rate_limited_until: Mutex::new(None),
+            telemetry: self.telemetry,
+            health: self.health,
+        })
+    }
+}
+
+/// Error body returned by the Buildkite API.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// Returns the proxies configured with the `HTTPS_PROXY`, `HTTP_PROXY` and
+/// `ALL_PROXY` environment variables, the more specific first.
+fn env_proxies() -> Result<Vec<reqwest::Proxy>> {
+    let mut proxies = Vec::new();
+    for (names, proxy) in [
+        (
+            ["HTTPS_PROXY", "https_proxy"],
+            reqwest::Proxy::https::<String> as fn(String) -> reqwest::Result<reqwest::Proxy>,
+        ),
+        (["HTTP_PROXY", "http_proxy"], reqwest::Proxy::http::<String>),
+        (["ALL_PROXY", "all_proxy"], reqwest::Proxy::all::<String>),
+    ] {
+        let Some(url) = names
+            .iter()
+            .find_map(|name| std::env::var(name).ok())
+            .filter(|url| !url.is_empty())
+        else {
+            continue;
+        };
+        proxies.push(proxy(url).map_err(|err| AgentApiError::Config(Arc::new(err)))?);
+    }
+    Ok(proxies)
+}
+
+/// Extracts the `message` field from an error response, truncated to a safe length.
+async fn error_message(response: reqwest::Response) -> Option<String> {
+    let body = response.bytes().await.ok()?;
+    let error = serde_json::from_slice::<ErrorResponse>(&body).ok()?;
+    Some(truncate(error.message.trim(), MAX_ERROR_MESSAGE_LEN))
+}
+
+fn truncate(message: &str, max_len: usize) -> String {
+    match message.char_indices().nth(max_len) {
+        None => message.to_string(),
+        Some((index, _)) => format!("{}...", &message[..index]),
+    }
+}
+
+fn format_message(message: &Option<String>) -> String {
+    message
+        .as_ref()
+        .map(|message| format!(": {}", message))
+        .unwrap_or_default()
+}
+
+/// Parses how long to wait from a rate limited response.
+///
+/// `Retry-After` may be a number of seconds or an HTTP date. Falls back to
+/// `RateLimit-Reset`, the number of seconds until the limit resets.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after = headers
+        .get("Retry-After")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            let value = value.trim();
+            value.parse().map(Duration::from_secs).ok().or_else(|| {
+                httpdate::parse_http_date(value)
+                    .ok()
+                    .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default())
+            })
+        });
+
+    retry_after.or_else(|| parse_rate_limit_reset(headers))
+}
+
+/// Returns how long until the limit resets if the response used the last
+/// request allowed.
+fn parse_rate_limit_exhausted(headers: &HeaderMap) -> Option<Duration> {
+    let remaining = headers
+        .get("RateLimit-Remaining")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())?;
+
+    if remaining > 0 {
+        return None;
+    }
+
+    parse_rate_limit_reset(headers)
+}
+
+fn parse_rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get("RateLimit-Reset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+impl AgentApiError {
+    /// Returns true if the request may succeed when retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AgentApiError::Transport(err) => !err.is_builder(),
+            AgentApiError::Timeout(_) => true,
+            AgentApiError::Server { status, .. } => matches!(
+                *status,
+                StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the backoff before the given retry, with jitter.
+    ///
+    /// Uses "equal jitter": half of the exponential backoff is fixed, the
+    /// other half is random.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=half);
+        half + jitter
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl MetricsProvider for BuildkiteMetrics {
+    async fn metrics(&self) -> Result<Arc<Metrics>> {
+        self.get().await.map(Arc::new)
+    }
+}
+
+impl fmt::Debug for BuildkiteMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the agent token.
+        f.debug_struct("BuildkiteMetrics")
+            .field("base_url", &self.base_url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("retry_policy", &self.retry_policy)
+            .field("request_timeout", &self.request_timeout)
+            .finish_non_exhaustive()
+    }
 }
 
 trait RequestBuilderExt {
@@ -95,8 +620,61 @@ impl RequestBuilderExt for reqwest::RequestBuilder {
     }
 }
 
+impl JobState {
+    pub const ALL: [JobState; 3] = [JobState::Scheduled, JobState::Waiting, JobState::Running];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|state| state.as_str().eq_ignore_ascii_case(value))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Scheduled => "scheduled",
+            JobState::Waiting => "waiting",
+            JobState::Running => "running",
+        }
+    }
+}
+
+impl JobQueue {
+    /// Returns the number of jobs in the given state.
+    pub fn count(&self, state: JobState) -> i64 {
+        match state {
+            JobState::Scheduled => self.scheduled,
+            JobState::Waiting => self.waiting,
+            JobState::Running => self.running,
+        }
+    }
+}
+
 impl Metrics {
     pub fn get_job_queue(&self, queue: &str) -> Option<&JobQueue> {
         self.jobs.queues.get(queue)
     }
+
+    /// Returns the agent metrics of the queues selected by `selector`.
+    pub fn agent_queues<'a>(
+        &'a self,
+        selector: &'a QueueSelector,
+    ) -> impl Iterator<Item = &'a AgentQueue> + 'a {
+        self.agents
+            .queues
+            .iter()
+            .filter(|(name, _)| selector.matches(name))
+            .map(|(_, queue)| queue)
+    }
+
+    /// Returns the job metrics of the queues selected by `selector`.
+    pub fn job_queues<'a>(
+        &'a self,
+        selector: &'a QueueSelector,
+    ) -> impl Iterator<Item = &'a JobQueue> + 'a {
+        self.jobs
+            .queues
+            .iter()
+            .filter(|(name, _)| selector.matches(name))
+            .map(|(_, queue)| queue)
+    }
 }
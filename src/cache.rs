@@ -0,0 +1,142 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+use tracing::{field, instrument, warn, Span};
+
+use crate::agent_api::{AgentApiError, Metrics, MetricsProvider, Result};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Caching options for [MetricsCache].
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// How long fetched metrics are considered fresh.
+    pub ttl: Duration,
+    /// If set, serve metrics up to this old when the upstream fails.
+    pub max_stale: Option<Duration>,
+}
+
+/// Caches metrics from another provider.
+///
+/// Concurrent callers that miss the cache share a single upstream request,
+/// and its error if it fails.
+pub struct MetricsCache<P> {
+    inner: P,
+    options: CacheOptions,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entry: Option<CacheEntry>,
+    last_error: Option<FailedFetch>,
+}
+
+struct CacheEntry {
+    metrics: Arc<Metrics>,
+    fetched_at: Instant,
+}
+
+struct FailedFetch {
+    err: AgentApiError,
+    failed_at: Instant,
+}
+
+impl<P> MetricsCache<P>
+where
+    P: MetricsProvider,
+{
+    pub fn new(inner: P, options: CacheOptions) -> Self {
+        Self {
+            inner,
+            options,
+            state: Mutex::default(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<P> MetricsProvider for MetricsCache<P>
+where
+    P: MetricsProvider,
+{
+    #[instrument(skip_all, fields(cache = field::Empty), err(Debug))]
+    async fn metrics(&self) -> Result<Arc<Metrics>> {
+        let queued_at = Instant::now();
+
+        // Holding the lock while fetching makes concurrent callers wait for
+        // the in-flight request instead of sending their own.
+        let mut state = self.state.lock().await;
+
+        if let Some(cached) = state.entry.as_ref() {
+            if cached.fetched_at.elapsed() < self.options.ttl {
+                Span::current().record("cache", "hit");
+                return Ok(cached.metrics.clone());
+            }
+        }
+
+        // A fetch that failed while this caller was waiting is not retried.
+        let shared_error = state
+            .last_error
+            .as_ref()
+            .filter(|failed| failed.failed_at >= queued_at)
+            .map(|failed| failed.err.clone());
+        let result = match shared_error {
+            Some(err) => Err(err),
+            None => {
+                Span::current().record("cache", "miss");
+                let result = self.inner.metrics().await;
+                if let Err(err) = &result {
+                    state.last_error = Some(FailedFetch {
+                        err: err.clone(),
+                        failed_at: Instant::now(),
+                    });
+                }
+                result
+            }
+        };
+
+        match result {
+            Ok(metrics) => {
+                state.entry = Some(CacheEntry {
+                    metrics: metrics.clone(),
+                    fetched_at: Instant::now(),
+                });
+                state.last_error = None;
+                Ok(metrics)
+            }
+            Err(err) => {
+                let stale = state.entry.as_ref().filter(|cached| {
+                    self.options
+                        .max_stale
+                        .map(|max_stale| cached.fetched_at.elapsed() < max_stale)
+                        .unwrap_or(false)
+                });
+
+                let Some(cached) = stale else {
+                    return Err(err);
+                };
+
+                Span::current().record("cache", "stale");
+                warn!(
+                    err = ?err,
+                    age = ?cached.fetched_at.elapsed(),
+                    "serving stale metrics"
+                );
+                Ok(cached.metrics.clone())
+            }
+        }
+    }
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            ttl: DEFAULT_CACHE_TTL,
+            max_stale: None,
+        }
+    }
+}
@@ -1,8 +1,26 @@
-use crate::agent_api::{BuildkiteMetrics, Metrics};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    agent_api::{AgentApiError, JobState, Metrics, MetricsProvider},
+    metadata::{MetricConfig, ParseOptions, ReportedValue, ScalerConfig},
+    queue::QueueSelector,
+    registry::MetricsRegistry,
+    schedule::{Clock, SystemClock},
+    shutdown::Shutdown,
+    telemetry::Telemetry,
+};
 
+use chrono::{DateTime, Utc};
 use proto::external_scaler_server::{ExternalScaler, ExternalScalerServer};
-use tonic::{codec::Streaming, Request, Response, Status};
-use tracing::{info, instrument};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tonic_reflection::server::{ServerReflection, ServerReflectionServer};
+use tracing::{info, info_span, instrument, warn, Instrument};
 
 use self::proto::{
     GetMetricSpecResponse, GetMetricsRequest, GetMetricsResponse, IsActiveResponse, MetricSpec,
@@ -11,61 +29,337 @@ use self::proto::{
 
 pub mod proto {
     tonic::include_proto!("externalscaler");
+
+    /// Encoded file descriptor set of the external scaler protocol, for gRPC reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("externalscaler_descriptor");
 }
 
-const DEFAULT_TARGET_WAITING_JOBS: i64 = 1;
+/// Returns the gRPC reflection service, describing the external scaler and
+/// health services to clients such as grpcurl.
+pub fn reflection_service() -> ServerReflectionServer<impl ServerReflection> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .expect("valid file descriptor sets")
+}
+
+const DEFAULT_STREAM_INTERVAL: Duration = Duration::from_secs(5);
+/// Reported values of scaled objects not polled for this long are forgotten.
+const REPORTED_VALUE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How the reported metric is computed from the queue metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricMode {
+    /// Jobs in the counted states.
+    Runnable,
+    /// Jobs in the counted states minus idle agents, at least zero.
+    IdleAdjusted,
+    /// Busy agents plus jobs in the counted states.
+    DesiredAgents,
+}
+
+/// A metric reported for a scaled object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MetricKind {
+    /// Job demand, computed according to the [MetricMode].
+    Jobs,
+    /// Busy agents on the queues.
+    BusyAgents,
+    /// Agents connected to the queues.
+    TotalAgents,
+}
 
 pub struct BuildkiteScaler {
-    client: BuildkiteMetrics,
+    registry: MetricsRegistry,
+    stream_interval: Duration,
+    strict_metadata: bool,
+    /// The last value reported for each metric with `maxMetricIncrease`.
+    reported_values: Mutex<HashMap<String, ReportedEntry>>,
+    clock: Arc<dyn Clock>,
+    telemetry: Option<Telemetry>,
+    shutdown: Shutdown,
+}
+
+struct ReportedEntry {
+    /// The value increases are limited from.
+    reported: ReportedValue,
+    last_seen: DateTime<Utc>,
 }
 
 impl BuildkiteScaler {
-    pub fn new(client: BuildkiteMetrics) -> Self {
-        Self { client }
+    /// Creates a scaler that queries a single organization.
+    pub fn new(client: impl MetricsProvider + 'static) -> Self {
+        Self::from_registry(MetricsRegistry::new().with_default(Arc::new(client)))
+    }
+
+    /// Creates a scaler that queries the organizations in the registry.
+    pub fn from_registry(registry: MetricsRegistry) -> Self {
+        Self {
+            registry,
+            stream_interval: DEFAULT_STREAM_INTERVAL,
+            strict_metadata: false,
+            reported_values: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            telemetry: None,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Sets how often `StreamIsActive` streams re-evaluate the queue.
+    pub fn with_stream_interval(mut self, interval: Duration) -> Self {
+        self.stream_interval = interval;
+        self
+    }
+
+    /// Rejects scaled objects with metadata keys not used by the scaler or KEDA.
+    pub fn with_strict_metadata(mut self, strict: bool) -> Self {
+        self.strict_metadata = strict;
+        self
+    }
+
+    /// Sets the clock used to evaluate `minRunnableSchedule`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Records requests and reported values in the given telemetry.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    /// Closes the `StreamIsActive` streams with `unavailable` when the shutdown is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
     pub fn into_service(self) -> ExternalScalerServer<Self> {
         ExternalScalerServer::new(self)
     }
+
+    /// Applies the metric value limits and the scheduled floor of the scaled
+    /// object, remembering the reported value when increases are limited.
+    fn limit_metric_value(
+        &self,
+        request: &ScaledObjectRef,
+        config: &ScalerConfig,
+        metric: &MetricConfig,
+        value: i64,
+    ) -> i64 {
+        let now = self.clock.now();
+        let floor = match metric.kind {
+            MetricKind::Jobs => config.runnable_floor(now),
+            _ => 0,
+        };
+
+        if config.max_metric_increase.is_none() {
+            return config.limit_metric_value(value, None, floor);
+        }
+
+        let key = format!(
+            "{}/{}/{}",
+            request.namespace,
+            request.name,
+            metric_name(&config.queue, metric.kind)
+        );
+        let mut reported_values = self.reported_values.lock().expect("reported values lock");
+        // Forget deleted scaled objects.
+        reported_values.retain(|_, entry| {
+            (now - entry.last_seen).num_seconds() <= REPORTED_VALUE_TTL.as_secs() as i64
+        });
+
+        let previous = match reported_values.get(&key) {
+            Some(entry) => entry.reported,
+            None => config.initial_reported_value(floor, now),
+        };
+        let increase_limit = config.increase_limit(&previous, now);
+        let limited = config.limit_metric_value(value, increase_limit, floor);
+
+        // While the increase is limited, the allowance keeps growing from the
+        // same point, however often KEDA asks.
+        let reported = if increase_limit.is_some_and(|limit| value > limit) {
+            previous
+        } else {
+            ReportedValue {
+                value: limited,
+                at: now,
+            }
+        };
+        reported_values.insert(
+            key,
+            ReportedEntry {
+                reported,
+                last_seen: now,
+            },
+        );
+        limited
+    }
+
+    fn observe_rpc<T>(
+        &self,
+        method: &str,
+        request: Option<&ScaledObjectRef>,
+        result: &Result<T, Status>,
+    ) {
+        if let Some(telemetry) = &self.telemetry {
+            let (namespace, name) = request
+                .map(|request| (request.namespace.as_str(), request.name.as_str()))
+                .unwrap_or_default();
+            telemetry.observe_rpc(method, namespace, name, result);
+        }
+    }
+
+    /// Parses the scaler configuration from the scaled object metadata.
+    #[allow(clippy::result_large_err)]
+    fn config(&self, request: &ScaledObjectRef) -> Result<ScalerConfig, Status> {
+        let options = ParseOptions {
+            strict: self.strict_metadata,
+            extra_keys: self
+                .registry
+                .token_providers()
+                .map(|token_providers| vec![token_providers.metadata_key().to_string()])
+                .unwrap_or_default(),
+        };
+
+        ScalerConfig::parse(&request.scaler_metadata, &options).map_err(|err| err.into_status())
+    }
+
+    /// Returns the metrics provider for the organization selected by the scaled object.
+    #[allow(clippy::result_large_err)]
+    fn provider(
+        &self,
+        request: &ScaledObjectRef,
+        config: &ScalerConfig,
+    ) -> Result<Arc<dyn MetricsProvider>, Status> {
+        if let Some(token_providers) = self.registry.token_providers() {
+            if let Some(token) = request.scaler_metadata.get(token_providers.metadata_key()) {
+                return token_providers
+                    .get_or_create(token)
+                    .map_err(IntoStatus::into_status);
+            }
+        }
+
+        let provider = match config.organization.as_deref() {
+            Some(name) => self.registry.organization(name),
+            None => self.registry.default_provider(),
+        };
+
+        provider.cloned().ok_or_else(|| {
+            let configured = self.registry.organization_names().collect::<Vec<_>>();
+            let message = match config.organization.as_deref() {
+                Some(name) => format!("unknown organization {}", name),
+                None => "organization not specified".to_string(),
+            };
+            Status::invalid_argument(format!(
+                "{}, configured organizations: [{}]",
+                message,
+                configured.join(", ")
+            ))
+        })
+    }
 }
 
 #[tonic::async_trait]
 impl ExternalScaler for BuildkiteScaler {
-    /// Returns true if the number of jobs waiting in the queue is greater than zero.
+    /// Returns true if the job metric of the queue is greater than `activationWaitingJobs`.
     #[instrument(skip_all, err(Debug))]
     async fn is_active(
         &self,
         request: Request<ScaledObjectRef>,
     ) -> Result<Response<IsActiveResponse>, Status> {
         let request = request.into_inner();
+        let result = self.handle_is_active(&request).await;
+        self.observe_rpc("is_active", Some(&request), &result);
+        result
+    }
 
-        let queue = request.require_queue()?;
-        let target_waiting_jobs = request.target_waiting_jobs()?;
+    type StreamIsActiveStream = ReceiverStream<Result<IsActiveResponse, Status>>;
 
-        let metrics = self.client.get().await.map_err(IntoStatus::into_status)?;
-        let runnable = metrics.job_queue_runnable(&queue);
+    /// Periodically re-evaluates the queue and sends a response every time its activity changes.
+    ///
+    /// The stream ends when the client disconnects, or with `unavailable` when
+    /// the scaler shuts down.
+    #[instrument(skip_all, err(Debug))]
+    async fn stream_is_active(
+        &self,
+        request: Request<ScaledObjectRef>,
+    ) -> Result<Response<Self::StreamIsActiveStream>, Status> {
+        let request = request.into_inner();
 
-        info!(
-            queue = queue,
-            runnable = runnable,
-            target_waiting_jobs = target_waiting_jobs,
-            "handle is_active"
-        );
+        if self.shutdown.is_triggered() {
+            return Err(shutting_down());
+        }
 
-        let response = IsActiveResponse {
-            result: runnable >= target_waiting_jobs,
-        };
+        let config = self.config(&request)?;
 
-        Ok(Response::new(response))
-    }
+        info!(
+            queue = %config.queue,
+            activation_waiting_jobs = config.activation_waiting_jobs,
+            "handle stream_is_active"
+        );
 
-    type StreamIsActiveStream = Streaming<IsActiveResponse>;
+        let client = self.provider(&request, &config)?;
+        let clock = self.clock.clone();
+        let shutdown = self.shutdown.clone();
+        let (tx, rx) = mpsc::channel(1);
+        let mut interval = tokio::time::interval(self.stream_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let span = info_span!("stream_is_active", queue = %config.queue);
+        tokio::spawn(
+            async move {
+                let mut last_active = None;
+                loop {
+                    let metrics = tokio::select! {
+                        _ = tx.closed() => break,
+                        _ = shutdown.wait() => {
+                            let _ = tx.send(Err(shutting_down())).await;
+                            break;
+                        }
+                        metrics = async {
+                            interval.tick().await;
+                            client.metrics().await
+                        } => metrics,
+                    };
+
+                    let active = match metrics {
+                        Ok(metrics) => {
+                            metrics.queue_metric(
+                                &config.queue,
+                                &config.job_states,
+                                config.metric_mode,
+                            ) > config.activation_waiting_jobs
+                                || config.runnable_floor(clock.now()) > 0
+                        }
+                        Err(err) => {
+                            warn!(err = ?err, "failed to fetch metrics");
+                            continue;
+                        }
+                    };
+
+                    if last_active == Some(active) {
+                        continue;
+                    }
+
+                    info!(active = active, "queue activity changed");
+                    last_active = Some(active);
+
+                    let response = IsActiveResponse { result: active };
+                    if tx.send(Ok(response)).await.is_err() {
+                        break;
+                    }
+                }
+
+                info!("stream closed");
+            }
+            .instrument(span),
+        );
 
-    async fn stream_is_active(
-        &self,
-        _request: Request<ScaledObjectRef>,
-    ) -> Result<Response<Self::StreamIsActiveStream>, Status> {
-        Err(Status::unimplemented("scaler is pull only"))
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     #[instrument(skip_all, err(Debug))]
@@ -74,69 +368,172 @@ impl ExternalScaler for BuildkiteScaler {
         request: Request<ScaledObjectRef>,
     ) -> Result<Response<GetMetricSpecResponse>, Status> {
         let request = request.into_inner();
+        let result = self.handle_get_metric_spec(&request).await;
+        self.observe_rpc("get_metric_spec", Some(&request), &result);
+        result
+    }
 
-        let queue = request.require_queue()?;
-        let target_waiting_jobs = request.target_waiting_jobs()?;
+    #[instrument(skip_all, err(Debug))]
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let request = request.into_inner();
+        let result = self.handle_get_metrics(&request).await;
+        self.observe_rpc("get_metrics", request.scaled_object_ref.as_ref(), &result);
+        result
+    }
+}
 
-        let metric_spec = MetricSpec {
-            metric_name: metric_name(&queue),
-            target_size: target_waiting_jobs,
-        };
+impl BuildkiteScaler {
+    async fn handle_is_active(
+        &self,
+        request: &ScaledObjectRef,
+    ) -> Result<Response<IsActiveResponse>, Status> {
+        let config = self.config(request)?;
+        let client = self.provider(request, &config)?;
+
+        let metrics = client.metrics().await.map_err(IntoStatus::into_status)?;
+        let runnable = metrics.queue_metric(&config.queue, &config.job_states, config.metric_mode);
+        let floor = config.runnable_floor(self.clock.now());
 
         info!(
-            queue = queue,
-            target_waiting_jobs = target_waiting_jobs,
-            "handle get_metric_spec"
+            queue = %config.queue,
+            runnable = runnable,
+            floor = floor,
+            activation_waiting_jobs = config.activation_waiting_jobs,
+            "handle is_active"
         );
 
-        let response = GetMetricSpecResponse {
-            metric_specs: vec![metric_spec],
+        let response = IsActiveResponse {
+            result: runnable > config.activation_waiting_jobs || floor > 0,
         };
 
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.observe_active(&request.namespace, &request.name, response.result);
+        }
+
         Ok(Response::new(response))
     }
 
-    #[instrument(skip_all, err(Debug))]
-    async fn get_metrics(
+    async fn handle_get_metric_spec(
         &self,
-        request: Request<GetMetricsRequest>,
-    ) -> Result<Response<GetMetricsResponse>, Status> {
-        let request = request.into_inner();
+        request: &ScaledObjectRef,
+    ) -> Result<Response<GetMetricSpecResponse>, Status> {
+        let config = self.config(request)?;
 
-        let queue = request
-            .scaled_object_ref
-            .ok_or_else(|| Status::invalid_argument("missing scaled object ref".to_string()))?
-            .require_queue()?;
+        let metric_specs = config
+            .metrics
+            .iter()
+            .map(|metric| MetricSpec {
+                metric_name: metric_name(&config.queue, metric.kind),
+                target_size: metric.target.target_size(),
+            })
+            .collect::<Vec<_>>();
 
-        let metrics = self.client.get().await.map_err(IntoStatus::into_status)?;
-        let runnable = metrics.job_queue_runnable(&queue);
+        info!(
+            queue = %config.queue,
+            metric_specs = ?metric_specs,
+            "handle get_metric_spec"
+        );
+
+        let response = GetMetricSpecResponse { metric_specs };
 
-        let metric = MetricValue {
-            metric_name: metric_name(&queue),
-            metric_value: runnable,
+        Ok(Response::new(response))
+    }
+
+    async fn handle_get_metrics(
+        &self,
+        request: &GetMetricsRequest,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let scaled_object_ref = request
+            .scaled_object_ref
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("missing scaled object ref".to_string()))?;
+        let config = self.config(scaled_object_ref)?;
+        let client = self.provider(scaled_object_ref, &config)?;
+
+        let metric = parse_metric_name(&request.metric_name, &config)?;
+        let queue = &config.queue;
+
+        let metrics = client.metrics().await.map_err(IntoStatus::into_status)?;
+        let raw_value =
+            metrics.metric_value(queue, metric.kind, &config.job_states, config.metric_mode);
+        let value = self.limit_metric_value(scaled_object_ref, &config, metric, raw_value);
+
+        let metric_value = MetricValue {
+            metric_name: metric_name(queue, metric.kind),
+            metric_value: metric.target.scale_value(value),
         };
 
-        info!(queue = queue, runnable = runnable, "handle get_metrics");
+        info!(
+            queue = %queue,
+            metric_kind = ?metric.kind,
+            raw_value = raw_value,
+            value = value,
+            target = %metric.target,
+            "handle get_metrics"
+        );
+
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.observe_metric_value(
+                &scaled_object_ref.namespace,
+                &scaled_object_ref.name,
+                &metric_value.metric_name,
+                metric_value.metric_value,
+            );
+        }
 
         let response = GetMetricsResponse {
-            metric_values: vec![metric],
+            metric_values: vec![metric_value],
         };
 
         Ok(Response::new(response))
     }
 }
 
-trait ScaledObjectRefExt {
-    fn require_queue(&self) -> Result<String, Status>;
-    fn target_waiting_jobs(&self) -> Result<i64, Status>;
-}
-
 trait MetricsExt {
-    fn job_queue_waiting(&self, queue: &str) -> i64;
-    fn job_queue_scheduled(&self, queue: &str) -> i64;
+    fn job_queue_count(&self, queue: &QueueSelector, state: JobState) -> i64;
 
-    fn job_queue_runnable(&self, queue: &str) -> i64 {
-        self.job_queue_waiting(queue) + self.job_queue_scheduled(queue)
+    fn job_queue_runnable(&self, queue: &QueueSelector, job_states: &[JobState]) -> i64 {
+        job_states
+            .iter()
+            .map(|state| self.job_queue_count(queue, *state))
+            .sum()
+    }
+
+    fn agent_queue_idle(&self, queue: &QueueSelector) -> i64;
+    fn agent_queue_busy(&self, queue: &QueueSelector) -> i64;
+    fn agent_queue_total(&self, queue: &QueueSelector) -> i64;
+
+    /// Returns the metric reported for the queue.
+    fn queue_metric(
+        &self,
+        queue: &QueueSelector,
+        job_states: &[JobState],
+        metric_mode: MetricMode,
+    ) -> i64 {
+        let runnable = self.job_queue_runnable(queue, job_states);
+        match metric_mode {
+            MetricMode::Runnable => runnable,
+            MetricMode::IdleAdjusted => (runnable - self.agent_queue_idle(queue)).max(0),
+            MetricMode::DesiredAgents => self.agent_queue_busy(queue) + runnable,
+        }
+    }
+
+    /// Returns the value of the given metric for the queue.
+    fn metric_value(
+        &self,
+        queue: &QueueSelector,
+        kind: MetricKind,
+        job_states: &[JobState],
+        metric_mode: MetricMode,
+    ) -> i64 {
+        match kind {
+            MetricKind::Jobs => self.queue_metric(queue, job_states, metric_mode),
+            MetricKind::BusyAgents => self.agent_queue_busy(queue),
+            MetricKind::TotalAgents => self.agent_queue_total(queue),
+        }
     }
 }
 
@@ -144,45 +541,139 @@ trait IntoStatus {
     fn into_status(self) -> Status;
 }
 
-impl IntoStatus for color_eyre::Report {
+impl IntoStatus for AgentApiError {
     fn into_status(self) -> Status {
-        Status::internal(format!("error: {}", self))
+        let message = self.to_string();
+        match self {
+            AgentApiError::Unauthorized { .. } => Status::unauthenticated(message),
+            AgentApiError::RateLimited { .. } => Status::resource_exhausted(message),
+            AgentApiError::Timeout(_) => Status::deadline_exceeded(message),
+            AgentApiError::NotFound { .. } => Status::not_found(message),
+            AgentApiError::Transport(_)
+            | AgentApiError::Server { .. }
+            | AgentApiError::NotFetched
+            | AgentApiError::Stale { .. } => Status::unavailable(message),
+            AgentApiError::UnexpectedStatus { .. }
+            | AgentApiError::Decode(_)
+            | AgentApiError::Config(_) => Status::internal(message),
+        }
     }
 }
 
-impl ScaledObjectRefExt for ScaledObjectRef {
-    fn require_queue(&self) -> Result<String, Status> {
-        self.scaler_metadata
-            .get("queue")
-            .cloned()
-            .ok_or_else(|| Status::invalid_argument("queue not specified"))
+impl MetricsExt for Metrics {
+    fn job_queue_count(&self, queue: &QueueSelector, state: JobState) -> i64 {
+        self.job_queues(queue).map(|queue| queue.count(state)).sum()
+    }
+
+    fn agent_queue_idle(&self, queue: &QueueSelector) -> i64 {
+        self.agent_queues(queue).map(|queue| queue.idle).sum()
     }
 
-    fn target_waiting_jobs(&self) -> Result<i64, Status> {
-        Ok(self
-            .scaler_metadata
-            .get("targetWaitingJobs")
-            .map(|target| target.parse())
-            .transpose()
-            .map_err(|_| Status::invalid_argument("targetWaitingJobs is not a number"))?
-            .unwrap_or(DEFAULT_TARGET_WAITING_JOBS))
+    fn agent_queue_busy(&self, queue: &QueueSelector) -> i64 {
+        self.agent_queues(queue).map(|queue| queue.busy).sum()
+    }
+
+    fn agent_queue_total(&self, queue: &QueueSelector) -> i64 {
+        self.agent_queues(queue).map(|queue| queue.total).sum()
     }
 }
 
-impl MetricsExt for Metrics {
-    fn job_queue_waiting(&self, queue: &str) -> i64 {
-        self.get_job_queue(queue)
-            .map(|queue| queue.waiting)
-            .unwrap_or(0)
+impl MetricMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "runnable" => Some(MetricMode::Runnable),
+            "idleAdjusted" => Some(MetricMode::IdleAdjusted),
+            "desiredAgents" => Some(MetricMode::DesiredAgents),
+            _ => None,
+        }
+    }
+}
+
+impl MetricKind {
+    pub const ALL: [MetricKind; 3] = [
+        MetricKind::Jobs,
+        MetricKind::BusyAgents,
+        MetricKind::TotalAgents,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Jobs => "jobs",
+            MetricKind::BusyAgents => "busyAgents",
+            MetricKind::TotalAgents => "totalAgents",
+        }
+    }
+
+    /// The metadata key with the target value of the metric.
+    pub(crate) fn target_key(&self) -> &'static str {
+        match self {
+            MetricKind::Jobs => "targetWaitingJobs",
+            MetricKind::BusyAgents => "targetBusyAgents",
+            MetricKind::TotalAgents => "targetTotalAgents",
+        }
     }
 
-    fn job_queue_scheduled(&self, queue: &str) -> i64 {
-        self.get_job_queue(queue)
-            .map(|queue| queue.scheduled)
-            .unwrap_or(0)
+    /// The suffix added to the metric name, empty for the job metric for compatibility.
+    fn name_suffix(&self) -> &'static str {
+        match self {
+            MetricKind::Jobs => "",
+            MetricKind::BusyAgents => "-busy-agents",
+            MetricKind::TotalAgents => "-total-agents",
+        }
     }
 }
 
-fn metric_name(queue: &str) -> String {
-    format!("buildkite-{}", queue)
+fn shutting_down() -> Status {
+    Status::unavailable("scaler is shutting down")
+}
+
+const METRIC_NAME_PREFIX: &str = "buildkite-";
+
+fn metric_name(queue: &QueueSelector, kind: MetricKind) -> String {
+    format!(
+        "{}{}{}",
+        METRIC_NAME_PREFIX,
+        queue.name(),
+        kind.name_suffix()
+    )
+}
+
+/// Parses a name returned by [metric_name] and checks it against the scaled object metadata.
+///
+/// Returns `invalid_argument` if the name is malformed or refers to another
+/// queue, and `not_found` if the metric is not configured for the scaled object.
+#[allow(clippy::result_large_err)]
+fn parse_metric_name<'a>(name: &str, config: &'a ScalerConfig) -> Result<&'a MetricConfig, Status> {
+    let Some(rest) = name.strip_prefix(METRIC_NAME_PREFIX) else {
+        return Err(Status::invalid_argument(format!(
+            "metric name {:?} must start with {}",
+            name, METRIC_NAME_PREFIX
+        )));
+    };
+
+    let queue = &config.queue;
+    let queue_name = queue.name();
+
+    // The job metric has no suffix, so check the other kinds first.
+    let kind = MetricKind::ALL
+        .into_iter()
+        .rev()
+        .find(|kind| rest.strip_suffix(kind.name_suffix()) == Some(queue_name.as_str()))
+        .ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "metric name {} does not match queue {}",
+                name, queue
+            ))
+        })?;
+
+    config.metric(kind).ok_or_else(|| {
+        Status::not_found(format!(
+            "metric {} is not configured for the scaled object",
+            name
+        ))
+    })
 }
@@ -0,0 +1,207 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{Instant, MissedTickBehavior};
+use tonic::server::NamedService;
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tracing::{info, warn};
+
+use crate::{
+    agent_api::{AgentApiError, MetricsProvider},
+    externalscaler::{proto::external_scaler_server::ExternalScalerServer, BuildkiteScaler},
+    registry::MetricsRegistry,
+    shutdown::Shutdown,
+};
+
+/// Outcome of the Buildkite fetches of every configured agent token.
+#[derive(Clone, Default)]
+pub struct HealthState {
+    sources: Arc<Mutex<BTreeMap<String, SourceHealth>>>,
+}
+
+/// Records the outcome of the fetches of a single agent token.
+#[derive(Clone)]
+pub struct FetchReporter {
+    state: HealthState,
+    source: String,
+}
+
+#[derive(Default)]
+struct SourceHealth {
+    last_success: Option<Instant>,
+    last_error: Option<String>,
+    unauthorized: bool,
+}
+
+/// Decides whether the scaler is ready to serve KEDA.
+///
+/// The scaler is ready when the last fetch of every configured agent token
+/// succeeded within `window`, none of the tokens was rejected, and it is not
+/// shutting down.
+#[derive(Clone)]
+pub struct Readiness {
+    state: HealthState,
+    window: Duration,
+    probes: Vec<Arc<dyn MetricsProvider>>,
+    shutdown: Shutdown,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a source, it is not ready until its first successful fetch.
+    pub fn reporter(&self, source: impl Into<String>) -> FetchReporter {
+        let source = source.into();
+        self.lock().entry(source.clone()).or_default();
+        FetchReporter {
+            state: self.clone(),
+            source,
+        }
+    }
+
+    /// Returns the problems of the sources that are not ready.
+    pub fn check(&self, window: Duration) -> Result<(), Vec<String>> {
+        let now = Instant::now();
+        let problems = self
+            .lock()
+            .iter()
+            .filter_map(|(source, health)| {
+                let stale = match health.last_success {
+                    Some(last_success) => now - last_success > window,
+                    None => true,
+                };
+                let problem = if health.unauthorized {
+                    "agent token rejected by buildkite".to_string()
+                } else if stale {
+                    format!("no successful fetch in the last {:?}", window)
+                } else {
+                    return None;
+                };
+                let problem = match &health.last_error {
+                    Some(err) => format!("{}: {} ({})", source, problem, err),
+                    None => format!("{}: {}", source, problem),
+                };
+                Some(problem)
+            })
+            .collect::<Vec<_>>();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, SourceHealth>> {
+        self.sources.lock().expect("health state lock")
+    }
+}
+
+impl FetchReporter {
+    pub fn record_success(&self) {
+        let mut sources = self.state.lock();
+        let health = sources.entry(self.source.clone()).or_default();
+        health.last_success = Some(Instant::now());
+        health.last_error = None;
+        health.unauthorized = false;
+    }
+
+    pub fn record_error(&self, err: &AgentApiError) {
+        let mut sources = self.state.lock();
+        let health = sources.entry(self.source.clone()).or_default();
+        health.last_error = Some(err.to_string());
+        health.unauthorized = matches!(err, AgentApiError::Unauthorized { .. });
+    }
+}
+
+impl Readiness {
+    pub fn new(state: HealthState, window: Duration) -> Self {
+        Self {
+            state,
+            window,
+            probes: Vec::new(),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Reports not ready as soon as the shutdown is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Fetches metrics from the registry providers when the state is not ready,
+    /// so that the scaler becomes ready before KEDA sends the first request.
+    pub fn with_probes(mut self, registry: &MetricsRegistry) -> Self {
+        self.probes = registry
+            .default_provider()
+            .into_iter()
+            .chain(
+                registry
+                    .organization_names()
+                    .filter_map(|name| registry.organization(name)),
+            )
+            .cloned()
+            .collect();
+        self
+    }
+
+    /// Returns the reasons the scaler is not ready.
+    pub async fn check(&self) -> Result<(), Vec<String>> {
+        if self.shutdown.is_triggered() {
+            return Err(vec!["shutting down".to_string()]);
+        }
+
+        if self.state.check(self.window).is_ok() {
+            return Ok(());
+        }
+
+        for probe in &self.probes {
+            // The outcome is recorded by the client.
+            let _ = probe.metrics().await;
+        }
+
+        self.state.check(self.window)
+    }
+
+    /// Updates the status of the gRPC health service every `interval`, and
+    /// immediately when the shutdown is triggered.
+    ///
+    /// Both the overall status and the external scaler service reflect readiness.
+    pub async fn report_grpc_health(self, mut reporter: HealthReporter, interval: Duration) {
+        let service_name = <ExternalScalerServer<BuildkiteScaler> as NamedService>::NAME;
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut last_status = None;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = self.shutdown.wait(), if last_status != Some(ServingStatus::NotServing) => {},
+            }
+
+            let status = match self.check().await {
+                Ok(()) => ServingStatus::Serving,
+                Err(problems) => {
+                    if last_status != Some(ServingStatus::NotServing) {
+                        warn!(problems = ?problems, "scaler not ready");
+                    }
+                    ServingStatus::NotServing
+                }
+            };
+
+            if last_status != Some(status) {
+                info!(status = %status, "health status changed");
+                last_status = Some(status);
+            }
+
+            reporter.set_service_status("", status).await;
+            reporter.set_service_status(service_name, status).await;
+        }
+    }
+}
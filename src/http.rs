@@ -0,0 +1,90 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+
+use crate::{health::Readiness, telemetry::Telemetry};
+
+/// HTTP server for Prometheus metrics and Kubernetes probes.
+///
+/// - `GET /metrics`: metrics in the Prometheus text format.
+/// - `GET /healthz`: liveness, always ok while the process serves requests.
+/// - `GET /readyz`: readiness, `503` with the reasons when not ready.
+#[derive(Clone, Default)]
+pub struct HttpServer {
+    telemetry: Option<Telemetry>,
+    readiness: Option<Readiness>,
+}
+
+impl HttpServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// Serves requests until the future is dropped.
+    pub async fn serve(self, address: SocketAddr) -> Result<(), hyper::Error> {
+        let server = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(request).await) }
+                }))
+            }
+        });
+
+        hyper::Server::try_bind(&address)?.serve(make_service).await
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET {
+            return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+        }
+
+        match request.uri().path() {
+            "/metrics" => match &self.telemetry {
+                Some(telemetry) => Response::builder()
+                    .header(CONTENT_TYPE, telemetry.content_type())
+                    .body(Body::from(telemetry.encode()))
+                    .expect("valid response"),
+                None => response(StatusCode::NOT_FOUND, Body::empty()),
+            },
+            "/healthz" => response(StatusCode::OK, Body::from("ok\n")),
+            "/readyz" => {
+                let result = match &self.readiness {
+                    Some(readiness) => readiness.check().await,
+                    None => Ok(()),
+                };
+                match result {
+                    Ok(()) => response(StatusCode::OK, Body::from("ok\n")),
+                    Err(problems) => response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Body::from(format!("{}\n", problems.join("\n"))),
+                    ),
+                }
+            }
+            _ => response(StatusCode::NOT_FOUND, Body::empty()),
+        }
+    }
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body)
+        .expect("valid response")
+}
@@ -1,4 +1,34 @@
 pub mod agent_api;
+pub mod cache;
 pub mod externalscaler;
+pub mod health;
+pub mod http;
+pub mod metadata;
+pub mod poller;
+pub mod queue;
+pub mod registry;
+pub mod schedule;
+pub mod shutdown;
+pub mod target;
+pub mod telemetry;
+pub mod tls;
 
-pub use crate::{agent_api::BuildkiteMetrics, externalscaler::BuildkiteScaler};
+pub use crate::{
+    agent_api::{
+        AgentApiError, BuildkiteMetrics, BuildkiteMetricsBuilder, JobState, MetricsProvider,
+        RetryPolicy,
+    },
+    cache::{CacheOptions, MetricsCache},
+    externalscaler::{reflection_service, BuildkiteScaler},
+    health::{FetchReporter, HealthState, Readiness},
+    http::HttpServer,
+    metadata::{ConfigError, ParseOptions, ReportedValue, ScalerConfig},
+    poller::{MetricsPoller, MetricsSnapshot},
+    queue::QueueSelector,
+    registry::{MetricsRegistry, TokenProviders},
+    schedule::{Clock, RunnableFloor, SystemClock},
+    shutdown::Shutdown,
+    target::MetricTarget,
+    telemetry::Telemetry,
+    tls::{serve_with_tls_reload, TlsError, TlsFiles},
+};
@@ -1,26 +1,151 @@
+use std::{
+    collections::BTreeMap, future::Future, net::SocketAddr, path::PathBuf, pin::Pin, sync::Arc,
+    time::Duration,
+};
+
 use clap::Parser;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
 use tonic::transport::Server;
-use tracing::{info, Subscriber};
+use tracing::{info, warn, Subscriber};
 use tracing_subscriber::{prelude::*, registry::LookupSpan, EnvFilter, Layer};
 
-use buildkite_keda_scaler::{BuildkiteMetrics, BuildkiteScaler};
+use buildkite_keda_scaler::{
+    agent_api::DEFAULT_USER_AGENT, reflection_service, serve_with_tls_reload, BuildkiteMetrics,
+    BuildkiteMetricsBuilder, BuildkiteScaler, CacheOptions, HealthState, HttpServer, MetricsCache,
+    MetricsPoller, MetricsProvider, MetricsRegistry, Readiness, RetryPolicy, Shutdown, Telemetry,
+    TlsFiles, TokenProviders,
+};
 
 static BUILDKITE_AGENT_API_URL: &str = "https://agent.buildkite.com";
+static BUILDKITE_AGENT_TOKEN_PREFIX: &str = "BUILDKITE_AGENT_TOKEN_";
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 pub type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, about, version, long_about = None)]
 pub struct Cli {
-    /// The Buildkite agent token.
+    /// The Buildkite agent token, used by ScaledObjects that don't select an organization.
     #[arg(long, env = "BUILDKITE_AGENT_TOKEN")]
-    pub agent_token: String,
+    pub agent_token: Option<String>,
+    /// JSON file with the agent tokens of named organizations.
+    ///
+    /// Organizations can also be configured with `BUILDKITE_AGENT_TOKEN_<NAME>`
+    /// environment variables.
+    #[arg(long, env)]
+    pub organizations_file: Option<PathBuf>,
+    /// Allow ScaledObjects to supply their own agent token in the scaler metadata,
+    /// for example from a KEDA TriggerAuthentication.
+    #[arg(long, env)]
+    pub allow_metadata_token: bool,
+    /// The scaler metadata key that contains the agent token.
+    #[arg(long, env, default_value = "agentToken")]
+    pub metadata_token_key: String,
+    /// Drop the client of an agent token from the scaler metadata when it has
+    /// not been used for this long, e.g. `10m`.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "10m")]
+    pub metadata_token_idle_timeout: Duration,
+    /// Reject ScaledObjects whose metadata contains keys unknown to the scaler,
+    /// e.g. a misspelled `targetWaitingJobs`.
+    #[arg(long, env)]
+    pub strict_metadata: bool,
     /// Buildkite agent API URL, defaults to `https://agent.buildkite.com`.
     #[arg(long, env)]
     pub agent_api_url: Option<String>,
     /// The address to listen on. Defaults to `0.0.0.0:9090`.
     #[arg(long, env)]
     pub address: Option<String>,
+    /// PEM certificate of the gRPC server. If set, the server only accepts TLS.
+    #[arg(long, env, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the gRPC server certificate.
+    #[arg(long, env, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM bundle of the CA that signs client certificates. If set, clients
+    /// must authenticate with a certificate (mutual TLS).
+    #[arg(long, env, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// How often the TLS files are checked for changes, e.g. `30s`.
+    #[arg(long, env, value_parser = parse_interval, default_value = "30s")]
+    pub tls_reload_interval: Duration,
+    /// Serve gRPC server reflection, so that tools like grpcurl can call the
+    /// scaler without the proto files.
+    #[arg(long, env)]
+    pub grpc_reflection: bool,
+    /// If set, serve Prometheus metrics on `/metrics` and the `/healthz` and
+    /// `/readyz` probes at this address, e.g. `0.0.0.0:9091`.
+    #[arg(long, env, alias = "metrics-address")]
+    pub http_address: Option<SocketAddr>,
+    /// The scaler is ready if the last Buildkite fetch of every agent token
+    /// succeeded within this window, e.g. `1m`.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1m")]
+    pub readiness_window: Duration,
+    /// How long in-flight requests can take to complete after SIGTERM or SIGINT, e.g. `10s`.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "10s")]
+    pub drain_timeout: Duration,
+    /// How often `StreamIsActive` streams re-evaluate the queue, e.g. `5s`.
+    #[arg(long, env, value_parser = parse_interval)]
+    pub stream_interval: Option<Duration>,
+    /// How long Buildkite metrics are cached, e.g. `5s`.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub cache_ttl: Option<Duration>,
+    /// If set, serve cached metrics up to this old when the Buildkite API fails.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub cache_max_stale: Option<Duration>,
+    /// If set, poll Buildkite metrics in the background at this interval instead of
+    /// fetching them when KEDA asks.
+    #[arg(long, env, value_parser = parse_interval)]
+    pub poll_interval: Option<Duration>,
+    /// Reject polled metrics older than this. Defaults to three poll intervals.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub max_staleness: Option<Duration>,
+    /// Maximum number of retries for failed Buildkite API requests.
+    #[arg(long, env)]
+    pub max_retries: Option<u32>,
+    /// Overall time budget for a Buildkite API request, including retries, e.g. `5s`.
+    ///
+    /// Keep this below KEDA's gRPC timeout.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub request_deadline: Option<Duration>,
+    /// Timeout to connect to the Buildkite API, e.g. `5s`.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of a single Buildkite API request, e.g. `5s`.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    pub request_timeout: Option<Duration>,
+    /// Proxy used for requests to the Buildkite API.
+    ///
+    /// If not set, the standard `HTTP_PROXY` and `HTTPS_PROXY` variables are used.
+    #[arg(long, env = "BUILDKITE_PROXY")]
+    pub proxy: Option<String>,
+    /// Comma-separated list of hosts that bypass the proxy, also applied to
+    /// the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` variables.
+    #[arg(long, env = "NO_PROXY")]
+    pub no_proxy: Option<String>,
+    /// User agent of the requests to the Buildkite API.
+    #[arg(long, env, default_value = DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+    /// Extra PEM bundles with root certificates to trust, e.g. for an egress proxy.
+    #[arg(long, env, value_delimiter = ',')]
+    pub ca_bundle: Vec<PathBuf>,
+}
+
+/// Organizations loaded from `--organizations-file`.
+///
+/// ```json
+/// { "organizations": { "acme": { "agentToken": "...", "agentApiUrl": "..." } } }
+/// ```
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrganizationsConfig {
+    organizations: BTreeMap<String, OrganizationConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrganizationConfig {
+    agent_token: String,
+    agent_api_url: Option<String>,
 }
 
 #[tokio::main]
@@ -30,24 +155,310 @@ async fn main() -> Result<()> {
 
     let args = Cli::parse();
 
-    let client = BuildkiteMetrics::new(
-        args.agent_api_url
-            .unwrap_or_else(|| BUILDKITE_AGENT_API_URL.to_string()),
-        Some(args.agent_token),
+    let telemetry = args.http_address.map(|_| Telemetry::new());
+    let health = HealthState::new();
+
+    let registry = load_registry(&args, telemetry.as_ref(), &health)?;
+    if registry.is_empty() && !args.allow_metadata_token {
+        return Err(eyre!(
+            "no agent token configured, set --agent-token, --organizations-file or --allow-metadata-token"
+        ));
+    }
+
+    let shutdown = Shutdown::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("shutdown signal received");
+            shutdown.trigger();
+        }
+    });
+
+    let readiness = Readiness::new(health, args.readiness_window)
+        .with_probes(&registry)
+        .with_shutdown(shutdown.clone());
+
+    let mut scaler = BuildkiteScaler::from_registry(registry)
+        .with_strict_metadata(args.strict_metadata)
+        .with_shutdown(shutdown.clone());
+    if let Some(stream_interval) = args.stream_interval {
+        scaler = scaler.with_stream_interval(stream_interval);
+    }
+    if let Some(telemetry) = &telemetry {
+        scaler = scaler.with_telemetry(telemetry.clone());
+    }
+
+    let http_server = async {
+        match (args.http_address, telemetry.clone()) {
+            (Some(address), Some(telemetry)) => {
+                info!("serving metrics and probes on {}", address);
+                HttpServer::new()
+                    .with_telemetry(telemetry)
+                    .with_readiness(readiness.clone())
+                    .serve(address)
+                    .await
+            }
+            _ => std::future::pending().await,
+        }
+    };
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(
+        readiness
+            .clone()
+            .report_grpc_health(health_reporter, HEALTH_CHECK_INTERVAL),
     );
 
-    let scaler = BuildkiteScaler::new(client);
+    let address: SocketAddr = args
+        .address
+        .clone()
+        .unwrap_or("0.0.0.0:9090".to_string())
+        .parse()?;
+    let scaler_service = scaler.into_service();
+    let reflection_service = args.grpc_reflection.then(reflection_service);
+    if reflection_service.is_some() {
+        info!("grpc reflection enabled");
+    }
+    let grpc_server: Pin<Box<dyn Future<Output = Result<()>>>> = match tls_files(&args) {
+        Some(files) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            info!(
+                mutual_tls = files.client_ca.is_some(),
+                "listening on {} with tls", address
+            );
+            let reload_interval = args.tls_reload_interval;
+            let shutdown = shutdown.clone();
+            Box::pin(async move {
+                serve_with_tls_reload(listener, files, reload_interval, shutdown, |mut server| {
+                    server
+                        .add_service(health_service.clone())
+                        .add_service(scaler_service.clone())
+                        .add_optional_service(reflection_service.clone())
+                })
+                .await?;
+                Ok(())
+            })
+        }
+        None => {
+            info!("listening on {}", address);
+            let server = Server::builder()
+                .add_service(health_service)
+                .add_service(scaler_service)
+                .add_optional_service(reflection_service)
+                .serve_with_shutdown(address, {
+                    let shutdown = shutdown.clone();
+                    async move { shutdown.wait().await }
+                });
+            Box::pin(async move { Ok(server.await?) })
+        }
+    };
 
-    let address = args.address.unwrap_or("0.0.0.0:9090".to_string()).parse()?;
-    info!("listening on {}", address);
-    Server::builder()
-        .add_service(scaler.into_service())
-        .serve(address)
-        .await?;
+    tokio::pin!(grpc_server);
+    tokio::pin!(http_server);
 
+    tokio::select! {
+        result = &mut grpc_server => return result,
+        result = &mut http_server => return Ok(result?),
+        _ = shutdown.wait() => {},
+    }
+
+    // Keep serving the probes while the in-flight requests complete.
+    info!(drain_timeout = ?args.drain_timeout, "draining requests");
+    tokio::select! {
+        result = tokio::time::timeout(args.drain_timeout, &mut grpc_server) => match result {
+            Ok(result) => result?,
+            Err(_) => warn!("drain timeout elapsed with requests in flight"),
+        },
+        result = &mut http_server => result?,
+    }
+
+    info!("shutdown complete");
     Ok(())
 }
 
+/// Returns the TLS files of the gRPC server, if configured.
+fn tls_files(args: &Cli) -> Option<TlsFiles> {
+    Some(TlsFiles {
+        cert: args.tls_cert.clone()?,
+        key: args.tls_key.clone()?,
+        client_ca: args.tls_client_ca.clone(),
+    })
+}
+
+/// Parses a duration used as a timer period, which must not be zero.
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(value) {
+        Ok(interval) if interval.is_zero() => Err("interval must be greater than zero".to_string()),
+        Ok(interval) => Ok(interval),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Waits for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Builds the metrics providers for the default token and all named organizations.
+fn load_registry(
+    args: &Cli,
+    telemetry: Option<&Telemetry>,
+    health: &HealthState,
+) -> Result<MetricsRegistry> {
+    let mut registry = MetricsRegistry::new();
+
+    if let Some(token) = &args.agent_token {
+        let client = client_builder(args, telemetry)?
+            .token(token.clone())
+            .health(health.reporter("default"))
+            .build()?;
+        registry = registry.with_default(provider(args, client));
+    }
+
+    let mut organizations = BTreeMap::new();
+
+    if let Some(path) = &args.organizations_file {
+        let content = std::fs::read(path)
+            .wrap_err_with(|| format!("failed to read organizations file {}", path.display()))?;
+        let config: OrganizationsConfig = serde_json::from_slice(&content)
+            .wrap_err_with(|| format!("failed to parse organizations file {}", path.display()))?;
+        organizations.extend(config.organizations);
+    }
+
+    for (key, token) in std::env::vars_os() {
+        // variables that are not valid unicode can't be agent tokens
+        let (Some(key), Some(token)) = (key.to_str(), token.to_str()) else {
+            continue;
+        };
+        let Some(name) = key.strip_prefix(BUILDKITE_AGENT_TOKEN_PREFIX) else {
+            continue;
+        };
+        if name.is_empty() {
+            return Err(eyre!(
+                "{} is missing the organization name",
+                BUILDKITE_AGENT_TOKEN_PREFIX
+            ));
+        }
+        let name = name.to_lowercase().replace('_', "-");
+        organizations.insert(
+            name,
+            OrganizationConfig {
+                agent_token: token.to_string(),
+                agent_api_url: None,
+            },
+        );
+    }
+
+    for (name, config) in organizations {
+        let mut builder = client_builder(args, telemetry)?
+            .token(config.agent_token)
+            .health(health.reporter(&name));
+        if let Some(agent_api_url) = config.agent_api_url {
+            builder = builder.base_url(agent_api_url);
+        }
+        info!(organization = name, "configured organization");
+        registry = registry.with_organization(name, provider(args, builder.build()?));
+    }
+
+    if args.allow_metadata_token {
+        info!(
+            metadata_key = args.metadata_token_key,
+            "agent tokens from scaler metadata enabled"
+        );
+        let builder = client_builder(args, telemetry)?;
+        let provider_args = args.clone();
+        // Always cached: a background poller per token would keep fetching
+        // with tokens that are no longer used.
+        let token_providers = TokenProviders::new(&args.metadata_token_key, move |token| {
+            let client = builder.clone().token(token).build()?;
+            Ok(cache_provider(&provider_args, client))
+        })
+        .with_idle_timeout(args.metadata_token_idle_timeout);
+        registry = registry.with_token_providers(token_providers);
+    }
+
+    Ok(registry)
+}
+
+/// Wraps the client in the background poller or the cache.
+fn provider(args: &Cli, client: BuildkiteMetrics) -> Arc<dyn MetricsProvider> {
+    if let Some(poll_interval) = args.poll_interval {
+        let max_staleness = args.max_staleness.unwrap_or(poll_interval * 3);
+        Arc::new(MetricsPoller::spawn(client, poll_interval, max_staleness))
+    } else {
+        cache_provider(args, client)
+    }
+}
+
+/// Wraps the client in the cache.
+fn cache_provider(args: &Cli, client: BuildkiteMetrics) -> Arc<dyn MetricsProvider> {
+    let mut cache_options = CacheOptions::default();
+    if let Some(ttl) = args.cache_ttl {
+        cache_options.ttl = ttl;
+    }
+    cache_options.max_stale = args.cache_max_stale;
+
+    Arc::new(MetricsCache::new(client, cache_options))
+}
+
+/// Returns a client builder configured from the command line arguments.
+fn client_builder(args: &Cli, telemetry: Option<&Telemetry>) -> Result<BuildkiteMetricsBuilder> {
+    let base_url = args
+        .agent_api_url
+        .clone()
+        .unwrap_or_else(|| BUILDKITE_AGENT_API_URL.to_string());
+
+    let mut retry_policy = RetryPolicy::default();
+    if let Some(max_retries) = args.max_retries {
+        retry_policy.max_retries = max_retries;
+    }
+    if let Some(deadline) = args.request_deadline {
+        retry_policy.deadline = deadline;
+    }
+
+    let mut builder = BuildkiteMetrics::builder(base_url)
+        .retry_policy(retry_policy)
+        .user_agent(&args.user_agent);
+
+    if let Some(timeout) = args.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = args.request_timeout {
+        builder = builder.request_timeout(timeout);
+    }
+    if let Some(proxy) = &args.proxy {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(no_proxy) = &args.no_proxy {
+        builder = builder.no_proxy(no_proxy);
+    }
+    for path in &args.ca_bundle {
+        let pem = std::fs::read(path)
+            .wrap_err_with(|| format!("failed to read CA bundle {}", path.display()))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    if let Some(telemetry) = telemetry {
+        builder = builder.telemetry(telemetry.clone());
+    }
+
+    Ok(builder)
+}
+
 pub fn init_tracing() {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
@@ -0,0 +1,475 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::Duration,
+};
+
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::{
+    agent_api::JobState,
+    externalscaler::{MetricKind, MetricMode},
+    queue::QueueSelector,
+    schedule::RunnableFloor,
+    target::MetricTarget,
+};
+
+const DEFAULT_TARGET: MetricTarget = MetricTarget::from_units(1);
+const DEFAULT_ACTIVATION_WAITING_JOBS: i64 = 0;
+const DEFAULT_JOB_STATES: [JobState; 2] = [JobState::Scheduled, JobState::Waiting];
+/// KEDA's default polling interval.
+const DEFAULT_MAX_METRIC_INCREASE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Metadata keys read by the scaler.
+const SCALER_KEYS: [&str; 16] = [
+    "queue",
+    "targetWaitingJobs",
+    "targetBusyAgents",
+    "targetTotalAgents",
+    "activationWaitingJobs",
+    "jobStates",
+    "metricMode",
+    "metrics",
+    "organization",
+    "minMetricValue",
+    "maxMetricValue",
+    "maxMetricIncrease",
+    "maxMetricIncreaseInterval",
+    "minRunnable",
+    "minRunnableSchedule",
+    "minRunnableTimezone",
+];
+
+/// Metadata keys of the KEDA external scaler trigger, forwarded to the scaler
+/// together with the scaler keys.
+const KEDA_KEYS: [&str; 7] = [
+    "scalerAddress",
+    "caCert",
+    "tlsCertFile",
+    "tlsClientCert",
+    "tlsClientKey",
+    "enableTLS",
+    "unsafeSsl",
+];
+
+/// The scaler configuration of a scaled object, parsed from its metadata.
+#[derive(Debug, Clone)]
+pub struct ScalerConfig {
+    pub queue: QueueSelector,
+    pub activation_waiting_jobs: i64,
+    pub job_states: Vec<JobState>,
+    pub metric_mode: MetricMode,
+    /// The reported metrics, sorted by kind.
+    pub metrics: Vec<MetricConfig>,
+    pub organization: Option<String>,
+    /// The reported metric values are at least this.
+    pub min_metric_value: Option<i64>,
+    /// The reported metric values are at most this.
+    pub max_metric_value: Option<i64>,
+    /// The maximum increase of a reported metric value per `max_metric_increase_interval`.
+    pub max_metric_increase: Option<i64>,
+    pub max_metric_increase_interval: Duration,
+    /// The minimum job metric during the `minRunnableSchedule` windows.
+    pub min_runnable: Option<RunnableFloor>,
+}
+
+/// A metric reported for a scaled object and its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricConfig {
+    pub kind: MetricKind,
+    pub target: MetricTarget,
+}
+
+/// A metric value reported to KEDA and when, from which increases are limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportedValue {
+    pub value: i64,
+    pub at: DateTime<Utc>,
+}
+
+/// Options used when parsing [ScalerConfig].
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Reject keys that are not read by the scaler or KEDA.
+    pub strict: bool,
+    /// Additional keys accepted in strict mode, e.g. the agent token key.
+    pub extra_keys: Vec<String>,
+}
+
+/// A problem with a single metadata key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub description: String,
+}
+
+/// All the problems found in the metadata of a scaled object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    violations: Vec<FieldViolation>,
+}
+
+impl ScalerConfig {
+    /// Parses the metadata, reporting every invalid key instead of stopping at the first.
+    pub fn parse(
+        metadata: &HashMap<String, String>,
+        options: &ParseOptions,
+    ) -> Result<Self, ConfigError> {
+        let mut errors = Violations::default();
+
+        if options.strict {
+            let mut unknown = metadata
+                .keys()
+                .filter(|key| {
+                    !SCALER_KEYS.contains(&key.as_str())
+                        && !KEDA_KEYS.contains(&key.as_str())
+                        && !options.extra_keys.contains(key)
+                })
+                .collect::<Vec<_>>();
+            unknown.sort();
+            for key in unknown {
+                errors.push(key, "unknown metadata key");
+            }
+        }
+
+        let queue = match metadata
+            .get("queue")
+            .and_then(|queue| QueueSelector::parse(queue))
+        {
+            Some(queue) => Some(queue),
+            None => {
+                errors.push("queue", "queue not specified");
+                None
+            }
+        };
+
+        let activation_waiting_jobs =
+            parse_optional(metadata, "activationWaitingJobs", 0, &mut errors)
+                .unwrap_or(DEFAULT_ACTIVATION_WAITING_JOBS);
+
+        let job_states = match metadata.get("jobStates") {
+            None => DEFAULT_JOB_STATES.to_vec(),
+            Some(value) => parse_list(
+                value,
+                "jobStates",
+                "state",
+                &mut errors,
+                JobState::parse,
+                || JobState::ALL.map(|state| state.as_str()).join(", "),
+            ),
+        };
+
+        let metric_mode = match metadata.get("metricMode") {
+            None => MetricMode::Runnable,
+            Some(value) => MetricMode::parse(value).unwrap_or_else(|| {
+                errors.push(
+                    "metricMode",
+                    format!(
+                        "metricMode {} is not one of runnable, idleAdjusted, desiredAgents",
+                        value
+                    ),
+                );
+                MetricMode::Runnable
+            }),
+        };
+
+        let mut targets = BTreeMap::new();
+        for kind in MetricKind::ALL {
+            let key = kind.target_key();
+            let target = match metadata.get(key) {
+                None => DEFAULT_TARGET,
+                Some(value) => MetricTarget::parse(value).unwrap_or_else(|| {
+                    errors.push(
+                        key,
+                        format!(
+                            "{} must be a number greater than zero with at most three decimals",
+                            key
+                        ),
+                    );
+                    DEFAULT_TARGET
+                }),
+            };
+            targets.insert(kind, target);
+        }
+
+        let kinds = match metadata.get("metrics") {
+            None => vec![MetricKind::Jobs],
+            Some(value) => parse_list(
+                value,
+                "metrics",
+                "metric",
+                &mut errors,
+                MetricKind::parse,
+                || MetricKind::ALL.map(|kind| kind.as_str()).join(", "),
+            ),
+        };
+        let metrics = kinds
+            .into_iter()
+            .map(|kind| MetricConfig {
+                kind,
+                target: targets[&kind],
+            })
+            .collect();
+
+        let organization = metadata.get("organization").cloned();
+
+        let min_metric_value = parse_optional(metadata, "minMetricValue", 0, &mut errors);
+        let max_metric_value = parse_optional(metadata, "maxMetricValue", 0, &mut errors);
+        let max_metric_increase = parse_optional(metadata, "maxMetricIncrease", 1, &mut errors);
+        let max_metric_increase_interval = match metadata.get("maxMetricIncreaseInterval") {
+            None => DEFAULT_MAX_METRIC_INCREASE_INTERVAL,
+            Some(value) => match humantime::parse_duration(value) {
+                Ok(interval) if !interval.is_zero() => interval,
+                _ => {
+                    errors.push(
+                        "maxMetricIncreaseInterval",
+                        "maxMetricIncreaseInterval must be a duration greater than zero, e.g. 30s",
+                    );
+                    DEFAULT_MAX_METRIC_INCREASE_INTERVAL
+                }
+            },
+        };
+        if let (Some(min), Some(max)) = (min_metric_value, max_metric_value) {
+            if min > max {
+                errors.push(
+                    "minMetricValue",
+                    "minMetricValue must not be greater than maxMetricValue",
+                );
+            }
+        }
+
+        let min_runnable = parse_min_runnable(metadata, &mut errors);
+
+        match queue {
+            Some(queue) if errors.0.is_empty() => Ok(ScalerConfig {
+                queue,
+                activation_waiting_jobs,
+                job_states,
+                metric_mode,
+                metrics,
+                organization,
+                min_metric_value,
+                max_metric_value,
+                max_metric_increase,
+                max_metric_increase_interval,
+                min_runnable,
+            }),
+            _ => Err(ConfigError {
+                violations: errors.0,
+            }),
+        }
+    }
+
+    /// Returns the configuration of the metric, if it is reported.
+    pub fn metric(&self, kind: MetricKind) -> Option<&MetricConfig> {
+        self.metrics.iter().find(|metric| metric.kind == kind)
+    }
+
+    /// Returns the minimum job metric at `now`, zero outside the scheduled windows.
+    pub fn runnable_floor(&self, now: DateTime<Utc>) -> i64 {
+        self.min_runnable
+            .as_ref()
+            .map(|floor| floor.at(now))
+            .unwrap_or(0)
+    }
+
+    /// The value increases are limited from when none was reported, e.g. after
+    /// a restart: the lowest value of the metric, one interval ago.
+    pub fn initial_reported_value(&self, floor: i64, now: DateTime<Utc>) -> ReportedValue {
+        let interval = chrono::Duration::from_std(self.max_metric_increase_interval)
+            .unwrap_or(chrono::Duration::zero());
+        ReportedValue {
+            value: floor.max(self.min_metric_value.unwrap_or(0)),
+            at: now - interval,
+        }
+    }
+
+    /// Returns the largest value that can be reported at `now`, allowing
+    /// `maxMetricIncrease` per `maxMetricIncreaseInterval` since `previous`.
+    pub fn increase_limit(&self, previous: &ReportedValue, now: DateTime<Utc>) -> Option<i64> {
+        let increase = self.max_metric_increase?;
+        let elapsed = (now - previous.at).num_milliseconds().max(0) as i128;
+        let interval = self.max_metric_increase_interval.as_millis().max(1) as i128;
+        let limit = previous.value as i128 + increase as i128 * elapsed / interval;
+        Some(limit.min(i64::MAX as i128) as i64)
+    }
+
+    /// Limits a metric value to `increase_limit`, raises it to `floor`, then
+    /// clamps it between `minMetricValue` and `maxMetricValue`.
+    pub fn limit_metric_value(&self, value: i64, increase_limit: Option<i64>, floor: i64) -> i64 {
+        let mut value = match increase_limit {
+            Some(limit) => value.min(limit),
+            None => value,
+        };
+        value = value.max(floor);
+        if let Some(max) = self.max_metric_value {
+            value = value.min(max);
+        }
+        if let Some(min) = self.min_metric_value {
+            value = value.max(min);
+        }
+        value
+    }
+}
+
+impl ConfigError {
+    pub fn violations(&self) -> &[FieldViolation] {
+        &self.violations
+    }
+
+    /// Converts the error to an `invalid_argument` status with a
+    /// `google.rpc.BadRequest` detail listing every violation.
+    pub fn into_status(self) -> Status {
+        let message = self.to_string();
+        let violations = self
+            .violations
+            .into_iter()
+            .map(|violation| {
+                tonic_types::FieldViolation::new(violation.field, violation.description)
+            })
+            .collect::<Vec<_>>();
+        Status::with_error_details(
+            Code::InvalidArgument,
+            message,
+            ErrorDetails::with_bad_request(violations),
+        )
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid scaler metadata: ")?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            if violation.description.starts_with(&violation.field) {
+                write!(f, "{}", violation.description)?;
+            } else {
+                write!(f, "{}: {}", violation.field, violation.description)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Default)]
+struct Violations(Vec<FieldViolation>);
+
+impl Violations {
+    fn push(&mut self, field: impl Into<String>, description: impl Into<String>) {
+        self.0.push(FieldViolation {
+            field: field.into(),
+            description: description.into(),
+        });
+    }
+}
+
+/// Parses an optional integer that must be at least `min`.
+fn parse_optional(
+    metadata: &HashMap<String, String>,
+    key: &str,
+    min: i64,
+    errors: &mut Violations,
+) -> Option<i64> {
+    let value = metadata.get(key)?;
+    match value.parse::<i64>() {
+        Ok(value) if value >= min => Some(value),
+        _ => {
+            errors.push(
+                key,
+                format!("{} must be an integer of at least {}", key, min),
+            );
+            None
+        }
+    }
+}
+
+/// Parses the `minRunnable`, `minRunnableSchedule` and `minRunnableTimezone` keys.
+fn parse_min_runnable(
+    metadata: &HashMap<String, String>,
+    errors: &mut Violations,
+) -> Option<RunnableFloor> {
+    let value = parse_optional(metadata, "minRunnable", 1, errors);
+    let timezone = match metadata.get("minRunnableTimezone") {
+        None => Some(Tz::UTC),
+        Some(timezone) => match timezone.parse::<Tz>() {
+            Ok(timezone) => Some(timezone),
+            Err(_) => {
+                errors.push(
+                    "minRunnableTimezone",
+                    format!("minRunnableTimezone {} is not an IANA timezone", timezone),
+                );
+                None
+            }
+        },
+    };
+
+    let Some(schedule) = metadata.get("minRunnableSchedule") else {
+        if metadata.contains_key("minRunnable") {
+            errors.push("minRunnable", "minRunnable requires minRunnableSchedule");
+        }
+        return None;
+    };
+
+    match RunnableFloor::parse(value.unwrap_or(1), schedule, timezone?) {
+        Ok(floor) => Some(floor),
+        Err(err) => {
+            errors.push(
+                "minRunnableSchedule",
+                format!("minRunnableSchedule: {}", err),
+            );
+            None
+        }
+    }
+}
+
+/// Parses a comma-separated list, returns the sorted and deduplicated values.
+fn parse_list<T: Ord>(
+    value: &str,
+    key: &str,
+    item_name: &str,
+    errors: &mut Violations,
+    parse: impl Fn(&str) -> Option<T>,
+    valid: impl Fn() -> String,
+) -> Vec<T> {
+    let mut values = Vec::new();
+    let mut has_unknown = false;
+    for item in value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        match parse(item) {
+            Some(item) => values.push(item),
+            None => {
+                has_unknown = true;
+                errors.push(
+                    key,
+                    format!(
+                        "{} contains unknown {} {}, valid {}s: {}",
+                        key,
+                        item_name,
+                        item,
+                        item_name,
+                        valid()
+                    ),
+                );
+            }
+        }
+    }
+
+    if values.is_empty() && !has_unknown {
+        errors.push(key, format!("{} is empty", key));
+    }
+
+    values.sort();
+    values.dedup();
+    values
+}
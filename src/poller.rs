@@ -0,0 +1,91 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::watch, time::MissedTickBehavior};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::agent_api::{AgentApiError, Metrics, MetricsProvider, Result};
+
+/// Metrics fetched by the [MetricsPoller].
+#[derive(Debug, Clone)]
+pub struct MetricsSnapshot {
+    pub metrics: Arc<Metrics>,
+    pub fetched_at: Instant,
+}
+
+/// Polls metrics in the background and serves the latest snapshot from memory.
+pub struct MetricsPoller {
+    snapshot: watch::Receiver<Option<MetricsSnapshot>>,
+    max_staleness: Duration,
+}
+
+impl MetricsPoller {
+    /// Spawns a task that fetches metrics from `client` every `interval`.
+    ///
+    /// Snapshots older than `max_staleness` are rejected. The task stops when
+    /// the poller is dropped.
+    pub fn spawn(
+        client: impl MetricsProvider + 'static,
+        interval: Duration,
+        max_staleness: Duration,
+    ) -> Self {
+        let (tx, rx) = watch::channel(None);
+
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        tokio::spawn(
+            async move {
+                loop {
+                    let metrics = tokio::select! {
+                        _ = tx.closed() => break,
+                        metrics = async {
+                            interval.tick().await;
+                            client.metrics().await
+                        } => metrics,
+                    };
+
+                    match metrics {
+                        Ok(metrics) => {
+                            debug!("metrics snapshot updated");
+                            tx.send_replace(Some(MetricsSnapshot {
+                                metrics,
+                                fetched_at: Instant::now(),
+                            }));
+                        }
+                        Err(err) => {
+                            warn!(err = ?err, "failed to poll metrics");
+                        }
+                    }
+                }
+            }
+            .instrument(info_span!("metrics_poller")),
+        );
+
+        Self {
+            snapshot: rx,
+            max_staleness,
+        }
+    }
+
+    /// Returns the latest snapshot, if any.
+    pub fn snapshot(&self) -> Option<MetricsSnapshot> {
+        self.snapshot.borrow().clone()
+    }
+}
+
+#[tonic::async_trait]
+impl MetricsProvider for MetricsPoller {
+    async fn metrics(&self) -> Result<Arc<Metrics>> {
+        let snapshot = self.snapshot().ok_or(AgentApiError::NotFetched)?;
+
+        let age = snapshot.fetched_at.elapsed();
+        if age > self.max_staleness {
+            return Err(AgentApiError::Stale { age });
+        }
+
+        Ok(snapshot.metrics)
+    }
+}
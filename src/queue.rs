@@ -0,0 +1,130 @@
+use std::fmt;
+
+/// Selects the Buildkite queues a scaled object scales on.
+///
+/// Parsed from a comma-separated list of queue names, each of which may be a
+/// glob pattern where `*` matches any sequence of characters and `?` matches
+/// a single character, for example `default,linux-*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueSelector {
+    patterns: Vec<String>,
+}
+
+impl QueueSelector {
+    /// Parses the selector, returns `None` if it doesn't contain any queue.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut patterns = value
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        if patterns.is_empty() {
+            return None;
+        }
+
+        patterns.sort();
+        patterns.dedup();
+
+        Some(Self { patterns })
+    }
+
+    /// Returns true if the queue is selected.
+    pub fn matches(&self, queue: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, queue))
+    }
+
+    /// Returns the queue name if the selector is a single queue without wildcards.
+    pub fn as_single_queue(&self) -> Option<&str> {
+        match self.patterns.as_slice() {
+            [queue] if !is_pattern(queue) => Some(queue),
+            _ => None,
+        }
+    }
+
+    /// Returns a name that identifies the set of queues.
+    ///
+    /// A single queue is used as is. Otherwise the name is built from the
+    /// sanitized patterns plus a hash of the selector, so that it's stable and
+    /// distinct for different selectors.
+    pub fn name(&self) -> String {
+        if let Some(queue) = self.as_single_queue() {
+            return queue.to_string();
+        }
+
+        let sanitized = self
+            .patterns
+            .iter()
+            .map(|pattern| {
+                pattern
+                    .chars()
+                    .map(|c| match c {
+                        '*' => 'x',
+                        c if c.is_ascii_alphanumeric() || c == '-' || c == '.' => c,
+                        _ => '_',
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("-");
+
+        format!("{}-{:08x}", sanitized, fnv1a(self.to_string().as_bytes()))
+    }
+}
+
+impl fmt::Display for QueueSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.patterns.join(","))
+    }
+}
+
+fn is_pattern(value: &str) -> bool {
+    value.contains(['*', '?'])
+}
+
+/// Matches `value` against a glob `pattern` supporting `*` and `?`.
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` in the pattern and the value position it matched up to.
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                v += 1;
+            }
+            Some(c) if *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// 32 bit FNV-1a hash, stable across builds and platforms.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::agent_api::{AgentApiError, Metrics, MetricsProvider, Result};
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Builds the metrics provider for an agent token.
+pub type ProviderFactory = dyn Fn(String) -> Result<Arc<dyn MetricsProvider>> + Send + Sync;
+
+/// Metrics providers for the organizations served by the scaler.
+///
+/// ScaledObjects select an organization by name. Those without one use the
+/// default provider, if configured.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    default: Option<Arc<dyn MetricsProvider>>,
+    organizations: BTreeMap<String, Arc<dyn MetricsProvider>>,
+    token_providers: Option<TokenProviders>,
+}
+
+/// Metrics providers for agent tokens supplied in the scaler metadata, for
+/// example from a KEDA `TriggerAuthentication`.
+///
+/// Providers are built on first use and reused for later requests with the
+/// same token. A provider is dropped when Buildkite rejects its token, or
+/// when it has not been used for the idle timeout.
+pub struct TokenProviders {
+    metadata_key: String,
+    factory: Box<ProviderFactory>,
+    idle_timeout: Duration,
+    providers: Arc<Mutex<HashMap<String, TokenEntry>>>,
+}
+
+struct TokenEntry {
+    provider: Arc<dyn MetricsProvider>,
+    last_used: Instant,
+}
+
+/// Removes the provider of its token when Buildkite rejects the token.
+struct TokenProvider {
+    token: String,
+    inner: Arc<dyn MetricsProvider>,
+    providers: Arc<Mutex<HashMap<String, TokenEntry>>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the provider used when no organization is selected.
+    pub fn with_default(mut self, provider: Arc<dyn MetricsProvider>) -> Self {
+        self.default = Some(provider);
+        self
+    }
+
+    /// Adds the provider for the named organization.
+    pub fn with_organization(
+        mut self,
+        name: impl Into<String>,
+        provider: Arc<dyn MetricsProvider>,
+    ) -> Self {
+        self.organizations.insert(name.into(), provider);
+        self
+    }
+
+    /// Allows scaled objects to bring their own agent token.
+    pub fn with_token_providers(mut self, token_providers: TokenProviders) -> Self {
+        self.token_providers = Some(token_providers);
+        self
+    }
+
+    /// Returns the providers for tokens supplied in the scaler metadata, if enabled.
+    pub fn token_providers(&self) -> Option<&TokenProviders> {
+        self.token_providers.as_ref()
+    }
+
+    /// Returns the default provider.
+    pub fn default_provider(&self) -> Option<&Arc<dyn MetricsProvider>> {
+        self.default.as_ref()
+    }
+
+    /// Returns the provider for the named organization.
+    pub fn organization(&self, name: &str) -> Option<&Arc<dyn MetricsProvider>> {
+        self.organizations.get(name)
+    }
+
+    /// Returns the names of the configured organizations, sorted.
+    pub fn organization_names(&self) -> impl Iterator<Item = &str> {
+        self.organizations.keys().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.organizations.is_empty()
+    }
+}
+
+impl TokenProviders {
+    /// Creates providers for tokens found under `metadata_key`.
+    pub fn new(
+        metadata_key: impl Into<String>,
+        factory: impl Fn(String) -> Result<Arc<dyn MetricsProvider>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            metadata_key: metadata_key.into(),
+            factory: Box::new(factory),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            providers: Default::default(),
+        }
+    }
+
+    /// Drops providers not used for `idle_timeout`, 10 minutes by default.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// The scaler metadata key that contains the agent token.
+    pub fn metadata_key(&self) -> &str {
+        &self.metadata_key
+    }
+
+    /// Returns the provider for the token, building it if needed.
+    pub fn get_or_create(&self, token: &str) -> Result<Arc<dyn MetricsProvider>> {
+        let mut providers = self.providers.lock().expect("token providers lock");
+        let now = Instant::now();
+        providers.retain(|_, entry| now - entry.last_used <= self.idle_timeout);
+
+        let inner = match providers.get_mut(token) {
+            Some(entry) => {
+                entry.last_used = now;
+                entry.provider.clone()
+            }
+            None => {
+                let provider = (self.factory)(token.to_string())?;
+                providers.insert(
+                    token.to_string(),
+                    TokenEntry {
+                        provider: provider.clone(),
+                        last_used: now,
+                    },
+                );
+                provider
+            }
+        };
+
+        Ok(Arc::new(TokenProvider {
+            token: token.to_string(),
+            inner,
+            providers: self.providers.clone(),
+        }))
+    }
+
+    /// Returns the number of providers currently kept.
+    pub fn len(&self) -> usize {
+        self.providers.lock().expect("token providers lock").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[tonic::async_trait]
+impl MetricsProvider for TokenProvider {
+    async fn metrics(&self) -> Result<Arc<Metrics>> {
+        let result = self.inner.metrics().await;
+        if let Err(AgentApiError::Unauthorized { .. }) = &result {
+            let mut providers = self.providers.lock().expect("token providers lock");
+            // The token may have been rebuilt since this provider was returned.
+            if providers
+                .get(&self.token)
+                .is_some_and(|entry| Arc::ptr_eq(&entry.provider, &self.inner))
+            {
+                providers.remove(&self.token);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+
+/// A source of the current time, replaced in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A minimum number of runnable jobs applied during cron windows.
+///
+/// Each window is a standard five-field cron expression (minute, hour, day of
+/// month, month, day of week) evaluated in the given timezone. The floor is
+/// applied for every minute matched by at least one expression, so
+/// `* 9-17 * * Mon-Fri` or `* 9-17 * * 1-5` covers working hours. As in
+/// standard cron, when both the day of month and the day of week are
+/// restricted a day matching either of them is included.
+#[derive(Clone)]
+pub struct RunnableFloor {
+    value: i64,
+    windows: Vec<Schedule>,
+    expressions: String,
+    timezone: Tz,
+}
+
+impl RunnableFloor {
+    /// Parses `;`-separated cron expressions.
+    pub fn parse(value: i64, expressions: &str, timezone: Tz) -> Result<Self, String> {
+        let mut windows = Vec::new();
+        for expression in expressions
+            .split(';')
+            .map(str::trim)
+            .filter(|expression| !expression.is_empty())
+        {
+            if expression.split_whitespace().count() != 5 {
+                return Err(format!(
+                    "cron expression {:?} must have five fields: minute hour day-of-month month day-of-week",
+                    expression
+                ));
+            }
+            let fields = expression.split_whitespace().collect::<Vec<_>>();
+            let day_of_week = standard_day_of_week(fields[4])
+                .map_err(|err| format!("invalid cron expression {:?}: {}", expression, err))?;
+            // the `cron` crate requires both day fields to match, standard cron
+            // either of them when both are restricted
+            let days = if fields[2].starts_with('*') || fields[4].starts_with('*') {
+                vec![(fields[2], day_of_week.as_str())]
+            } else {
+                vec![(fields[2], "*"), ("*", day_of_week.as_str())]
+            };
+            for (day_of_month, day_of_week) in days {
+                let schedule = Schedule::from_str(&format!(
+                    "0 {} {} {} {} {}",
+                    fields[0], fields[1], day_of_month, fields[3], day_of_week
+                ))
+                .map_err(|err| format!("invalid cron expression {:?}: {}", expression, err))?;
+                windows.push(schedule);
+            }
+        }
+
+        if windows.is_empty() {
+            return Err("no cron expression".to_string());
+        }
+
+        Ok(Self {
+            value,
+            windows,
+            expressions: expressions.to_string(),
+            timezone,
+        })
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    /// Returns true if `now` is inside one of the windows.
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        let Some(minute) = now.with_second(0).and_then(|now| now.with_nanosecond(0)) else {
+            return false;
+        };
+        let local = minute.with_timezone(&self.timezone);
+        self.windows.iter().any(|window| window.includes(local))
+    }
+
+    /// Returns the floor at `now`, zero outside the windows.
+    pub fn at(&self, now: DateTime<Utc>) -> i64 {
+        if self.is_active_at(now) {
+            self.value
+        } else {
+            0
+        }
+    }
+}
+
+impl fmt::Debug for RunnableFloor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunnableFloor")
+            .field("value", &self.value)
+            .field("windows", &self.expressions)
+            .field("timezone", &self.timezone)
+            .finish()
+    }
+}
+
+const DAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Rewrites a day-of-week field with standard cron numbers, 0 to 7 from Sunday
+/// to Sunday, into day names.
+///
+/// The `cron` crate numbers days from 1 for Sunday, so `1-5` would silently
+/// mean Sunday to Thursday.
+fn standard_day_of_week(field: &str) -> Result<String, String> {
+    if !field.bytes().any(|byte| byte.is_ascii_digit()) {
+        return Ok(field.to_string());
+    }
+
+    let mut days = BTreeSet::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("invalid day of week step {:?}", step)),
+            },
+            None => (item, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (day_number(start)?, day_number(end)?),
+            None if step.is_some() => (day_number(range)?, 6),
+            None => (day_number(range)?, day_number(range)?),
+        };
+        if start > end {
+            return Err(format!("invalid day of week range {:?}", range));
+        }
+        days.extend((start..=end).step_by(step.unwrap_or(1)).map(|day| day % 7));
+    }
+
+    Ok(days
+        .into_iter()
+        .map(|day| DAY_NAMES[day])
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+fn day_number(day: &str) -> Result<usize, String> {
+    if let Some(index) = DAY_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+    {
+        return Ok(index);
+    }
+    match day.parse::<usize>() {
+        Ok(day) if day <= 7 => Ok(day),
+        _ => Err(format!("invalid day of week {:?}", day)),
+    }
+}
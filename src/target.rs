@@ -0,0 +1,83 @@
+use std::fmt;
+
+const MILLIS_PER_UNIT: i64 = 1000;
+
+/// A positive metric target with up to three decimal places, e.g. `2.5`.
+///
+/// KEDA only accepts integer targets and values. Integer targets are reported
+/// as they are. Fractional targets are reported in milli-units, and so are the
+/// values of their metric, so that the ratio computed by the HPA is unchanged.
+/// For example, a target of `2.5` with 10 runnable jobs is reported as a
+/// target of `2500` and a value of `10000`, which Kubernetes shows as the
+/// quantities `2500` and `10k`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricTarget {
+    millis: i64,
+}
+
+impl MetricTarget {
+    /// Creates an integer target.
+    pub const fn from_units(units: i64) -> Self {
+        Self {
+            millis: units * MILLIS_PER_UNIT,
+        }
+    }
+
+    /// Parses a decimal number, returns `None` if it is not a number, has
+    /// more than three decimal places, or is not greater than zero.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (units, decimals) = value.split_once('.').unwrap_or((value, ""));
+
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if units.is_empty() || !is_digits(units) || !is_digits(decimals) || decimals.len() > 3 {
+            return None;
+        }
+
+        let units = units.parse::<i64>().ok()?;
+        let decimals = format!("{:0<3}", decimals).parse::<i64>().ok()?;
+        let millis = units.checked_mul(MILLIS_PER_UNIT)?.checked_add(decimals)?;
+
+        if millis <= 0 {
+            return None;
+        }
+
+        Some(Self { millis })
+    }
+
+    /// Returns true if the target has no fractional part.
+    pub fn is_integer(&self) -> bool {
+        self.millis % MILLIS_PER_UNIT == 0
+    }
+
+    /// The value reported as `MetricSpec.target_size`.
+    pub fn target_size(&self) -> i64 {
+        if self.is_integer() {
+            self.millis / MILLIS_PER_UNIT
+        } else {
+            self.millis
+        }
+    }
+
+    /// Converts a metric value to the same scale as [MetricTarget::target_size].
+    pub fn scale_value(&self, value: i64) -> i64 {
+        if self.is_integer() {
+            value
+        } else {
+            value.saturating_mul(MILLIS_PER_UNIT)
+        }
+    }
+}
+
+impl fmt::Display for MetricTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = self.millis / MILLIS_PER_UNIT;
+        let millis = self.millis % MILLIS_PER_UNIT;
+        if millis == 0 {
+            write!(f, "{}", units)
+        } else {
+            let decimals = format!("{:03}", millis);
+            write!(f, "{}.{}", units, decimals.trim_end_matches('0'))
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tonic::Status;
+
+use crate::agent_api::Metrics;
+
+/// Prometheus metrics about the scaler and the Buildkite queues.
+#[derive(Clone)]
+pub struct Telemetry {
+    registry: Registry,
+    queue_jobs: IntGaugeVec,
+    queue_agents: IntGaugeVec,
+    api_requests: IntCounterVec,
+    api_request_duration: HistogramVec,
+    rpc_requests: IntCounterVec,
+    reported_metric: IntGaugeVec,
+    reported_active: IntGaugeVec,
+    /// Queues last reported by each organization, to remove the gauges of
+    /// queues that disappear.
+    known_queues: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let queue_jobs = IntGaugeVec::new(
+            Opts::new("buildkite_queue_jobs", "Jobs on the queue by state."),
+            &["organization", "queue", "state"],
+        )
+        .expect("valid metric");
+        let queue_agents = IntGaugeVec::new(
+            Opts::new("buildkite_queue_agents", "Agents on the queue by state."),
+            &["organization", "queue", "state"],
+        )
+        .expect("valid metric");
+        let api_requests = IntCounterVec::new(
+            Opts::new(
+                "buildkite_api_requests_total",
+                "Requests to the Buildkite agent API by response status.",
+            ),
+            &["status"],
+        )
+        .expect("valid metric");
+        let api_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "buildkite_api_request_duration_seconds",
+                "Time until the Buildkite agent API responded, by response status.",
+            ),
+            &["status"],
+        )
+        .expect("valid metric");
+        let rpc_requests = IntCounterVec::new(
+            Opts::new(
+                "keda_scaler_requests_total",
+                "External scaler requests by method, ScaledObject and result code.",
+            ),
+            &["method", "namespace", "name", "code"],
+        )
+        .expect("valid metric");
+        let reported_metric = IntGaugeVec::new(
+            Opts::new(
+                "keda_scaler_metric_value",
+                "Last metric value reported to KEDA.",
+            ),
+            &["namespace", "name", "metric"],
+        )
+        .expect("valid metric");
+        let reported_active = IntGaugeVec::new(
+            Opts::new(
+                "keda_scaler_active",
+                "Last activity reported to KEDA, 1 if active.",
+            ),
+            &["namespace", "name"],
+        )
+        .expect("valid metric");
+
+        for collector in [
+            Box::new(queue_jobs.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(queue_agents.clone()),
+            Box::new(api_requests.clone()),
+            Box::new(api_request_duration.clone()),
+            Box::new(rpc_requests.clone()),
+            Box::new(reported_metric.clone()),
+            Box::new(reported_active.clone()),
+        ] {
+            registry.register(collector).expect("unique metric");
+        }
+
+        Self {
+            registry,
+            queue_jobs,
+            queue_agents,
+            api_requests,
+            api_request_duration,
+            rpc_requests,
+            reported_metric,
+            reported_active,
+            known_queues: Default::default(),
+        }
+    }
+
+    /// Records a request to the Buildkite agent API.
+    ///
+    /// `status` is the HTTP status code, or `timeout` or `error` if there was no response.
+    pub fn observe_api_request(&self, status: &str, duration: Duration) {
+        self.api_requests.with_label_values(&[status]).inc();
+        self.api_request_duration
+            .with_label_values(&[status])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Updates the queue gauges with metrics fetched from Buildkite.
+    pub fn observe_metrics(&self, metrics: &Metrics) {
+        let organization = metrics.organization.slug.as_str();
+
+        for (queue, jobs) in &metrics.jobs.queues {
+            for (state, value) in [
+                ("scheduled", jobs.scheduled),
+                ("running", jobs.running),
+                ("waiting", jobs.waiting),
+                ("total", jobs.total),
+            ] {
+                self.queue_jobs
+                    .with_label_values(&[organization, queue, state])
+                    .set(value);
+            }
+        }
+
+        for (queue, agents) in &metrics.agents.queues {
+            for (state, value) in [
+                ("idle", agents.idle),
+                ("busy", agents.busy),
+                ("total", agents.total),
+            ] {
+                self.queue_agents
+                    .with_label_values(&[organization, queue, state])
+                    .set(value);
+            }
+        }
+
+        let queues = metrics
+            .jobs
+            .queues
+            .keys()
+            .chain(metrics.agents.queues.keys())
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        let mut known_queues = self.known_queues.lock().expect("known queues lock");
+        let previous = known_queues
+            .insert(organization.to_string(), queues.clone())
+            .unwrap_or_default();
+        for queue in previous.difference(&queues) {
+            for state in ["scheduled", "running", "waiting", "total"] {
+                let _ = self
+                    .queue_jobs
+                    .remove_label_values(&[organization, queue, state]);
+            }
+            for state in ["idle", "busy", "total"] {
+                let _ = self
+                    .queue_agents
+                    .remove_label_values(&[organization, queue, state]);
+            }
+        }
+    }
+
+    /// Records the result of an external scaler request.
+    pub fn observe_rpc<T>(
+        &self,
+        method: &str,
+        namespace: &str,
+        name: &str,
+        result: &Result<T, Status>,
+    ) {
+        let code = match result {
+            Ok(_) => tonic::Code::Ok,
+            Err(status) => status.code(),
+        };
+        self.rpc_requests
+            .with_label_values(&[method, namespace, name, &format!("{:?}", code)])
+            .inc();
+    }
+
+    /// Records the metric value reported to KEDA.
+    pub fn observe_metric_value(&self, namespace: &str, name: &str, metric: &str, value: i64) {
+        self.reported_metric
+            .with_label_values(&[namespace, name, metric])
+            .set(value);
+    }
+
+    /// Records the activity reported to KEDA.
+    pub fn observe_active(&self, namespace: &str, name: &str, active: bool) {
+        self.reported_active
+            .with_label_values(&[namespace, name])
+            .set(active as i64);
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("encode metrics");
+        String::from_utf8(buffer).expect("metrics are utf-8")
+    }
+
+    /// The content type of [Telemetry::encode].
+    pub fn content_type(&self) -> &'static str {
+        prometheus::TEXT_FORMAT
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+use tokio_stream::Stream;
+use tonic::transport::{server::Router, Certificate, Identity, Server, ServerTlsConfig};
+use tracing::{info, warn};
+
+use crate::shutdown::Shutdown;
+
+/// Errors returned when serving with TLS.
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("invalid tls configuration: {0}")]
+    Config(#[source] tonic::transport::Error),
+    #[error("grpc server failed: {0}")]
+    Server(#[source] tonic::transport::Error),
+}
+
+/// PEM files of the server certificate and, for mutual TLS, of the client CA.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// If set, clients must present a certificate signed by this CA.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Reads the files into a server TLS configuration.
+    pub fn load(&self) -> Result<ServerTlsConfig, TlsError> {
+        let cert = read(&self.cert)?;
+        let key = read(&self.key)?;
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(client_ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(read(client_ca)?));
+        }
+        Ok(config)
+    }
+
+    /// Hash of the files content, used to detect rotated certificates.
+    fn fingerprint(&self) -> Result<u64, TlsError> {
+        let mut hasher = DefaultHasher::new();
+        for path in [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            read(path)?.hash(&mut hasher);
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// Serves gRPC with TLS on the listener until the shutdown is triggered.
+///
+/// The files are checked every `reload_interval`. When they change, a new
+/// server is started with the new certificates on the same listener, and the
+/// previous one stops accepting connections and drains its requests. Invalid
+/// files, e.g. a key rotated before its certificate, keep the previous
+/// certificates until the next check.
+pub async fn serve_with_tls_reload<F>(
+    listener: TcpListener,
+    files: TlsFiles,
+    reload_interval: Duration,
+    shutdown: Shutdown,
+    router: F,
+) -> Result<(), TlsError>
+where
+    F: Fn(Server) -> Router,
+{
+    let listener = Arc::new(listener);
+    let mut fingerprint = files.fingerprint()?;
+    let mut current = start(&listener, &files, &shutdown, &router)?;
+    let mut draining: Vec<JoinHandle<_>> = Vec::new();
+
+    let mut interval = tokio::time::interval(reload_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.reset();
+
+    loop {
+        tokio::select! {
+            result = &mut current.handle => return finish(result, draining).await,
+            _ = interval.tick() => {}
+        }
+
+        // Forget the previous servers once they are drained.
+        draining.retain(|handle| !handle.is_finished());
+
+        let new_fingerprint = match files.fingerprint() {
+            Ok(new_fingerprint) if new_fingerprint != fingerprint => new_fingerprint,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!(err = %err, "failed to read tls files");
+                continue;
+            }
+        };
+
+        match start(&listener, &files, &shutdown, &router) {
+            Ok(generation) => {
+                info!("tls certificates changed, reloaded");
+                fingerprint = new_fingerprint;
+                let previous = std::mem::replace(&mut current, generation);
+                let _ = previous.stop.send(());
+                draining.push(previous.handle);
+            }
+            Err(err) => warn!(err = %err, "failed to reload tls certificates"),
+        }
+    }
+}
+
+/// A server started with a given TLS configuration.
+struct Generation {
+    handle: JoinHandle<Result<(), tonic::transport::Error>>,
+    stop: oneshot::Sender<()>,
+}
+
+fn start<F>(
+    listener: &Arc<TcpListener>,
+    files: &TlsFiles,
+    shutdown: &Shutdown,
+    router: &F,
+) -> Result<Generation, TlsError>
+where
+    F: Fn(Server) -> Router,
+{
+    let server = Server::builder()
+        .tls_config(files.load()?)
+        .map_err(TlsError::Config)?;
+
+    let (stop, stopped) = oneshot::channel();
+    let shutdown = shutdown.clone();
+    let signal = async move {
+        tokio::select! {
+            _ = shutdown.wait() => {}
+            _ = stopped => {}
+        }
+    };
+
+    let incoming = SharedIncoming(listener.clone());
+    let handle = tokio::spawn(router(server).serve_with_incoming_shutdown(incoming, signal));
+    Ok(Generation { handle, stop })
+}
+
+/// Waits for the servers still draining requests after a reload.
+async fn finish(
+    result: Result<Result<(), tonic::transport::Error>, tokio::task::JoinError>,
+    draining: Vec<JoinHandle<Result<(), tonic::transport::Error>>>,
+) -> Result<(), TlsError> {
+    for handle in draining {
+        let _ = handle.await;
+    }
+
+    match result {
+        Ok(result) => result.map_err(TlsError::Server),
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Accepts connections from a listener shared by the server generations.
+struct SharedIncoming(Arc<TcpListener>);
+
+impl Stream for SharedIncoming {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
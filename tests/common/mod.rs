@@ -0,0 +1,58 @@
+use serde_json::json;
+
+/// A Buildkite metrics response with jobs waiting on the `small` and `large` queues.
+pub fn metrics_body() -> serde_json::Value {
+    json!({
+        "agents": {
+            "idle": 3,
+            "busy": 2,
+            "total": 5,
+            "queues": {
+                "default": {
+                    "idle": 1,
+                    "busy": 0,
+                    "total": 1,
+                },
+                "small": {
+                    "idle": 0,
+                    "busy": 1,
+                    "total": 1,
+                },
+                "large": {
+                    "idle": 2,
+                    "busy": 1,
+                    "total": 3,
+                },
+            },
+        },
+        "jobs": {
+            "scheduled": 0,
+            "running": 0,
+            "waiting": 0,
+            "total": 0,
+            "queues": {
+                "default": {
+                    "scheduled": 0,
+                    "running": 0,
+                    "waiting": 0,
+                    "total": 0,
+                },
+                "small": {
+                    "scheduled": 0,
+                    "running": 2,
+                    "waiting": 1,
+                    "total": 3,
+                },
+                "large": {
+                    "scheduled": 0,
+                    "running": 0,
+                    "waiting": 5,
+                    "total": 5,
+                },
+            },
+        },
+        "organization": {
+            "slug": "test"
+        },
+    })
+}
@@ -0,0 +1,314 @@
+use std::time::{Duration, Instant};
+
+use buildkite_keda_scaler::{
+    agent_api::DEFAULT_USER_AGENT, AgentApiError, BuildkiteMetrics, RetryPolicy,
+};
+use color_eyre::Result;
+use serde_json::json;
+use wiremock::{
+    matchers::{header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+mod common;
+
+use common::metrics_body;
+
+#[tokio::test]
+async fn test_retry_transient_errors() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+    let metrics = client.get().await?;
+    assert_eq!(metrics.organization.slug, "test");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_gives_up() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(502))
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+    let err = client.get().await.unwrap_err();
+    assert!(matches!(err, AgentApiError::Server { .. }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_respects_deadline() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(RetryPolicy {
+            max_retries: 100,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+            deadline: Duration::from_millis(300),
+        })
+        .build()?;
+
+    let start = Instant::now();
+    assert!(client.get().await.is_err());
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_retry_on_client_errors() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+    let err = client.get().await.unwrap_err();
+    assert!(matches!(err, AgentApiError::Decode(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limited() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+
+    let err = client.get().await.unwrap_err();
+    let AgentApiError::RateLimited { retry_after } = err else {
+        panic!("expected rate limited error, got {:?}", err);
+    };
+    assert!(retry_after > Duration::from_secs(50));
+
+    // the client backs off without sending more requests
+    let err = client.get().await.unwrap_err();
+    assert!(matches!(err, AgentApiError::RateLimited { .. }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limit_exhausted() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(metrics_body())
+                .insert_header("RateLimit-Remaining", "0")
+                .insert_header("RateLimit-Reset", "30"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+
+    // the last allowed request succeeds, the next one waits for the reset
+    assert!(client.get().await.is_ok());
+    let err = client.get().await.unwrap_err();
+    assert!(matches!(err, AgentApiError::RateLimited { .. }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_kinds() -> Result<()> {
+    let server = MockServer::start().await;
+
+    let err = error_for_status(&server, 401).await;
+    assert!(matches!(err, AgentApiError::Unauthorized { .. }));
+
+    let err = error_for_status(&server, 403).await;
+    assert!(matches!(err, AgentApiError::Unauthorized { .. }));
+
+    let err = error_for_status(&server, 404).await;
+    assert!(matches!(err, AgentApiError::NotFound { .. }));
+
+    let err = error_for_status(&server, 500).await;
+    assert!(matches!(err, AgentApiError::Server { .. }));
+
+    let err = error_for_status(&server, 418).await;
+    assert!(matches!(err, AgentApiError::UnexpectedStatus { .. }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_message() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(
+            ResponseTemplate::new(401).set_body_json(json!({ "message": "Invalid agent token" })),
+        )
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()?;
+    let err = client.get().await.unwrap_err();
+    let AgentApiError::Unauthorized { message, .. } = &err else {
+        panic!("expected unauthorized error, got {:?}", err);
+    };
+    assert_eq!(message.as_deref(), Some("Invalid agent token"));
+    assert!(err.to_string().contains("Invalid agent token"));
+
+    server.reset().await;
+
+    // long messages are truncated
+    let long_message = "x".repeat(10_000);
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({ "message": long_message })))
+        .mount(&server)
+        .await;
+
+    let err = client.get().await.unwrap_err();
+    let AgentApiError::Server { message, .. } = &err else {
+        panic!("expected server error, got {:?}", err);
+    };
+    assert!(message.as_ref().unwrap().len() < 300);
+
+    server.reset().await;
+
+    // html bodies are not included
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(403).set_body_string("<html>Forbidden</html>"))
+        .mount(&server)
+        .await;
+
+    let err = client.get().await.unwrap_err();
+    let AgentApiError::Unauthorized { message, .. } = &err else {
+        panic!("expected unauthorized error, got {:?}", err);
+    };
+    assert!(message.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_timeout() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(metrics_body())
+                .set_delay(Duration::from_secs(2)),
+        )
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .request_timeout(Duration::from_millis(100))
+        .retry_policy(RetryPolicy {
+            max_retries: 0,
+            ..retry_policy()
+        })
+        .build()?;
+
+    let err = client.get().await.unwrap_err();
+    assert!(matches!(err, AgentApiError::Timeout(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_agent() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .and(header("user-agent", DEFAULT_USER_AGENT))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri()).build()?;
+    client.get().await?;
+
+    Ok(())
+}
+
+async fn error_for_status(server: &MockServer, status: u16) -> AgentApiError {
+    server.reset().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(status))
+        .mount(server)
+        .await;
+
+    let client = BuildkiteMetrics::builder(server.uri())
+        .retry_policy(retry_policy())
+        .build()
+        .unwrap();
+    client.get().await.unwrap_err()
+}
+
+fn retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+        deadline: Duration::from_secs(5),
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use buildkite_keda_scaler::{
+    AgentApiError, BuildkiteMetrics, CacheOptions, MetricsCache, MetricsProvider,
+};
+use color_eyre::Result;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+mod common;
+
+use common::metrics_body;
+
+#[tokio::test]
+async fn test_concurrent_requests_are_coalesced() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::new(server.uri(), None);
+    let cache = MetricsCache::new(
+        client,
+        CacheOptions {
+            ttl: Duration::from_secs(60),
+            max_stale: None,
+        },
+    );
+
+    let (first, second, third) = tokio::join!(cache.metrics(), cache.metrics(), cache.metrics());
+    assert_eq!(first?.organization.slug, "test");
+    assert_eq!(second?.organization.slug, "test");
+    assert_eq!(third?.organization.slug, "test");
+
+    // still fresh, served from the cache
+    let metrics = cache.metrics().await?;
+    assert_eq!(metrics.organization.slug, "test");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_errors() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(404).set_delay(Duration::from_millis(200)))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::new(server.uri(), None);
+    let cache = MetricsCache::new(client, CacheOptions::default());
+
+    // callers waiting for the failed request get its error instead of retrying
+    let (first, second, third) = tokio::join!(cache.metrics(), cache.metrics(), cache.metrics());
+    for result in [first, second, third] {
+        assert!(matches!(result, Err(AgentApiError::NotFound { .. })));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_serve_stale_on_error() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    {
+        // errors are returned if stale data is not allowed
+        let client = BuildkiteMetrics::new(server.uri(), None);
+        let cache = MetricsCache::new(
+            client,
+            CacheOptions {
+                ttl: Duration::ZERO,
+                max_stale: None,
+            },
+        );
+
+        assert!(cache.metrics().await.is_ok());
+        assert!(cache.metrics().await.is_err());
+    }
+
+    server.reset().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    {
+        // stale data is served for a bounded period
+        let client = BuildkiteMetrics::new(server.uri(), None);
+        let cache = MetricsCache::new(
+            client,
+            CacheOptions {
+                ttl: Duration::ZERO,
+                max_stale: Some(Duration::from_millis(500)),
+            },
+        );
+
+        assert!(cache.metrics().await.is_ok());
+        assert!(cache.metrics().await.is_ok());
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(cache.metrics().await.is_err());
+    }
+
+    Ok(())
+}
@@ -1,23 +1,39 @@
-use std::{collections::HashMap, future::Future, net::SocketAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use buildkite_keda_scaler::{
     externalscaler::proto::{
         external_scaler_client::ExternalScalerClient, GetMetricsRequest, ScaledObjectRef,
     },
-    BuildkiteMetrics, BuildkiteScaler,
+    AgentApiError, BuildkiteMetrics, BuildkiteScaler, Clock, MetricsProvider, MetricsRegistry,
+    Shutdown, Telemetry, TokenProviders,
 };
+use chrono::{DateTime, TimeZone, Utc};
 use color_eyre::Result;
 use rand::Rng;
 use serde_json::json;
 use tonic::{
     transport::{Channel, Server},
-    Request,
+    Code, Request,
 };
+use tonic_types::StatusExt;
 use wiremock::{
-    matchers::{method, path},
+    matchers::{header, method, path},
     Mock, MockServer, ResponseTemplate,
 };
 
+mod common;
+
+use common::metrics_body;
+
 #[tokio::test]
 async fn test_is_active() -> Result<()> {
     let (server, client) = setup().await?;
@@ -52,7 +68,7 @@ async fn test_is_active() -> Result<()> {
         }
 
         {
-            // customize target waiting jobs
+            // target waiting jobs doesn't change activation
             let scaler_metadata = HashMap::from([
                 ("queue".to_string(), "large".to_string()),
                 ("targetWaitingJobs".to_string(), "100".to_string()),
@@ -64,10 +80,40 @@ async fn test_is_active() -> Result<()> {
                 scaler_metadata,
             });
 
+            let response = client.is_active(request).await.unwrap().into_inner();
+            assert!(response.result);
+        }
+
+        {
+            // customize activation waiting jobs
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("activationWaitingJobs".to_string(), "5".to_string()),
+            ]);
+
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
             let response = client.is_active(request).await.unwrap().into_inner();
             assert!(!response.result);
         }
 
+        {
+            // small queue has one job waiting, above the activation threshold
+            let scaler_metadata = HashMap::from([("queue".to_string(), "small".to_string())]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let response = client.is_active(request).await.unwrap().into_inner();
+            assert!(response.result);
+        }
+
         {
             // queue is required
             let scaler_metadata = HashMap::from([]);
@@ -91,6 +137,210 @@ async fn test_is_active() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_stream_is_active() -> Result<()> {
+    let (server, client) = setup().await?;
+
+    let test = async {
+        let mut client = client.await;
+
+        {
+            // default queue has no waiting jobs, so it's not active
+            let scaler_metadata = HashMap::from([("queue".to_string(), "default".to_string())]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let mut stream = client.stream_is_active(request).await.unwrap().into_inner();
+            let response = stream.message().await.unwrap().unwrap();
+            assert!(!response.result);
+        }
+
+        {
+            // large queue has jobs waiting, so it's active
+            let scaler_metadata = HashMap::from([("queue".to_string(), "large".to_string())]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let mut stream = client.stream_is_active(request).await.unwrap().into_inner();
+            let response = stream.message().await.unwrap().unwrap();
+            assert!(response.result);
+        }
+
+        {
+            // queue is required
+            let scaler_metadata = HashMap::from([]);
+
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let response = client.stream_is_active(request).await;
+            assert!(response.is_err());
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_is_active_changes() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let scaler = BuildkiteScaler::new(client).with_stream_interval(Duration::from_millis(100));
+    let (server, client) = serve(scaler)?;
+
+    let test = async {
+        let mut client = client.await;
+
+        // default queue has no waiting jobs, so it's not active
+        let scaler_metadata = HashMap::from([("queue".to_string(), "default".to_string())]);
+        let request = Request::new(ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        });
+
+        let mut stream = client.stream_is_active(request).await.unwrap().into_inner();
+        let response = stream.message().await.unwrap().unwrap();
+        assert!(!response.result);
+
+        // jobs are now waiting on the default queue
+        let mut body = metrics_body();
+        body["jobs"]["queues"]["default"]["waiting"] = json!(2);
+        metrics.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/v3/metrics"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&metrics)
+            .await;
+
+        let response = tokio::time::timeout(Duration::from_secs(2), stream.message())
+            .await
+            .expect("activity change")
+            .unwrap()
+            .unwrap();
+        assert!(response.result);
+
+        // no message while the activity does not change
+        let next = tokio::time::timeout(Duration::from_millis(500), stream.message()).await;
+        assert!(next.is_err());
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_is_active_disconnect() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let scaler = BuildkiteScaler::new(client).with_stream_interval(Duration::from_millis(100));
+    let (server, client) = serve(scaler)?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let scaler_metadata = HashMap::from([("queue".to_string(), "large".to_string())]);
+        let request = Request::new(ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        });
+
+        let mut stream = client.stream_is_active(request).await.unwrap().into_inner();
+        let response = stream.message().await.unwrap().unwrap();
+        assert!(response.result);
+
+        // the queue is polled while the stream is open
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let polled = metrics.received_requests().await.unwrap().len();
+        assert!(polled > 1);
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let polled = metrics.received_requests().await.unwrap().len();
+
+        // the task stopped polling
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(metrics.received_requests().await.unwrap().len(), polled);
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_is_active_shutdown() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let shutdown = Shutdown::new();
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let (server, client) = serve(BuildkiteScaler::new(client).with_shutdown(shutdown.clone()))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let object_ref = ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata: HashMap::from([("queue".to_string(), "large".to_string())]),
+        };
+
+        let mut stream = client
+            .stream_is_active(Request::new(object_ref.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        let response = stream.message().await.unwrap().unwrap();
+        assert!(response.result);
+
+        shutdown.trigger();
+
+        let status = stream.message().await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+
+        // new streams are rejected
+        let status = client
+            .stream_is_active(Request::new(object_ref))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_get_metrics_spec() -> Result<()> {
     let (server, client) = setup().await?;
@@ -224,7 +474,7 @@ async fn test_get_metrics() -> Result<()> {
             };
             let request = Request::new(GetMetricsRequest {
                 scaled_object_ref: Some(object_ref),
-                metric_name: "buildkite-default".to_string(),
+                metric_name: "buildkite-large".to_string(),
             });
 
             let response = client.get_metrics(request).await.unwrap().into_inner();
@@ -244,7 +494,7 @@ async fn test_get_metrics() -> Result<()> {
             };
             let request = Request::new(GetMetricsRequest {
                 scaled_object_ref: Some(object_ref),
-                metric_name: "buildkite-default".to_string(),
+                metric_name: "buildkite-missing".to_string(),
             });
 
             let response = client.get_metrics(request).await.unwrap().into_inner();
@@ -262,92 +512,892 @@ async fn test_get_metrics() -> Result<()> {
     Ok(())
 }
 
-async fn mock_metrics(server: &MockServer) {
-    let body = json!({
-        "agents": {
-            "idle": 1,
-            "busy": 2,
-            "total": 3,
-            "queues": {
-                "default": {
-                    "idle": 1,
-                    "busy": 0,
-                    "total": 1,
-                },
-                "small": {
-                    "idle": 0,
-                    "busy": 1,
-                    "total": 1,
-                },
-                "large": {
-                    "idle": 0,
-                    "busy": 1,
-                    "total": 1,
-                },
-            },
-        },
-        "jobs": {
-            "scheduled": 0,
-            "running": 0,
-            "waiting": 0,
-            "total": 0,
-            "queues": {
-                "default": {
-                    "scheduled": 0,
-                    "running": 0,
-                    "waiting": 0,
-                    "total": 0,
-                },
-                "small": {
-                    "scheduled": 0,
-                    "running": 0,
-                    "waiting": 1,
-                    "total": 1,
-                },
-                "large": {
-                    "scheduled": 0,
-                    "running": 0,
-                    "waiting": 5,
-                    "total": 5,
-                },
-            },
-        },
-        "organization": {
-            "slug": "test"
-        },
-    });
+#[tokio::test]
+async fn test_get_metrics_metric_name() -> Result<()> {
+    let (server, client) = setup().await?;
 
-    Mock::given(method("GET"))
-        .and(path("/v3/metrics"))
-        .respond_with(ResponseTemplate::new(200).set_body_json(body))
-        .mount(server)
-        .await;
+    let test = async {
+        let mut client = client.await;
+
+        let scaler_metadata = HashMap::from([
+            ("queue".to_string(), "large".to_string()),
+            ("metrics".to_string(), "jobs,busyAgents".to_string()),
+        ]);
+        let object_ref = ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        };
+
+        for (metric_name, code) in [
+            // another queue
+            ("buildkite-default", Code::InvalidArgument),
+            ("buildkite-default-busy-agents", Code::InvalidArgument),
+            // not produced by the scaler
+            ("large", Code::InvalidArgument),
+            ("", Code::InvalidArgument),
+            // not configured for the scaled object
+            ("buildkite-large-total-agents", Code::NotFound),
+        ] {
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref.clone()),
+                metric_name: metric_name.to_string(),
+            });
+
+            let status = client.get_metrics(request).await.unwrap_err();
+            assert_eq!(status.code(), code, "metric name {:?}", metric_name);
+        }
+
+        {
+            // queue names that look like a metric suffix
+            let scaler_metadata =
+                HashMap::from([("queue".to_string(), "large-busy-agents".to_string())]);
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: "buildkite-large-busy-agents".to_string(),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            let metric = response.metric_values.first().unwrap();
+            assert_eq!(metric.metric_name, "buildkite-large-busy-agents");
+            assert_eq!(metric.metric_value, 0);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
 }
 
-async fn setup() -> Result<(
-    impl Future<Output = ()>,
-    impl Future<Output = ExternalScalerClient<Channel>>,
-)> {
-    let auth_token = "test_token".to_string();
+#[tokio::test]
+async fn test_get_metrics_multiple_queues() -> Result<()> {
+    let (server, client) = setup().await?;
 
-    let metrics = MockServer::start().await;
-    mock_metrics(&metrics).await;
+    let test = async {
+        let mut client = client.await;
 
-    let client = BuildkiteMetrics::new(metrics.uri(), Some(auth_token));
-    let scaler = BuildkiteScaler::new(client);
-    let mut rng = rand::thread_rng();
-    let port = rng.gen_range(9_000..10_000);
-    let address: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
+        for (queue, expected) in [("small,large", 6), ("l*", 5), ("missing-*", 0)] {
+            let scaler_metadata = HashMap::from([("queue".to_string(), queue.to_string())]);
+
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+
+            let spec = client
+                .get_metric_spec(Request::new(object_ref.clone()))
+                .await
+                .unwrap()
+                .into_inner();
+            let metric_name = spec.metric_specs.first().unwrap().metric_name.clone();
+
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: metric_name.clone(),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            assert!(response.metric_values.len() == 1);
+            let metrics = response.metric_values.first().unwrap();
+            assert_eq!(metrics.metric_name, metric_name);
+            assert_eq!(metrics.metric_value, expected);
+        }
+    };
 
-    let server = {
-        let address = address;
-        async move {
-            let result = Server::builder()
-                .add_service(scaler.into_service())
-                .serve(address)
-                .await;
-            assert!(result.is_ok());
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_metrics_job_states() -> Result<()> {
+    let (server, client) = setup().await?;
+
+    let test = async {
+        let mut client = client.await;
+
+        for (job_states, expected) in [
+            ("running", 2),
+            ("Running, waiting", 3),
+            ("scheduled", 0),
+            ("waiting,waiting", 1),
+        ] {
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "small".to_string()),
+                ("jobStates".to_string(), job_states.to_string()),
+            ]);
+
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: "buildkite-small".to_string(),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            let metrics = response.metric_values.first().unwrap();
+            assert_eq!(metrics.metric_value, expected, "jobStates {}", job_states);
         }
+
+        for job_states in ["finished", ""] {
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "small".to_string()),
+                ("jobStates".to_string(), job_states.to_string()),
+            ]);
+
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let status = client.get_metric_spec(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_metrics_metric_mode() -> Result<()> {
+    let (server, client) = setup().await?;
+
+    let test = async {
+        let mut client = client.await;
+
+        for (queue, metric_mode, expected) in [
+            ("large", "runnable", 5),
+            ("large", "idleAdjusted", 3),
+            ("default", "idleAdjusted", 0),
+            ("large", "desiredAgents", 6),
+            ("small", "desiredAgents", 2),
+        ] {
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), queue.to_string()),
+                ("metricMode".to_string(), metric_mode.to_string()),
+            ]);
+
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: format!("buildkite-{}", queue),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            let metrics = response.metric_values.first().unwrap();
+            assert_eq!(
+                metrics.metric_value, expected,
+                "queue {} metricMode {}",
+                queue, metric_mode
+            );
+        }
+
+        {
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("metricMode".to_string(), "agents".to_string()),
+            ]);
+
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let status = client.is_active(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiple_metrics() -> Result<()> {
+    let (server, client) = setup().await?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let scaler_metadata = HashMap::from([
+            ("queue".to_string(), "large".to_string()),
+            ("metrics".to_string(), "jobs,busyAgents".to_string()),
+            ("targetWaitingJobs".to_string(), "2".to_string()),
+            ("targetBusyAgents".to_string(), "4".to_string()),
+        ]);
+        let object_ref = ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        };
+
+        let response = client
+            .get_metric_spec(Request::new(object_ref.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.metric_specs.len(), 2);
+
+        let jobs = &response.metric_specs[0];
+        assert_eq!(jobs.metric_name, "buildkite-large");
+        assert_eq!(jobs.target_size, 2);
+
+        let busy_agents = &response.metric_specs[1];
+        assert_eq!(busy_agents.metric_name, "buildkite-large-busy-agents");
+        assert_eq!(busy_agents.target_size, 4);
+
+        for (metric_name, expected) in [("buildkite-large", 5), ("buildkite-large-busy-agents", 1)]
+        {
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref.clone()),
+                metric_name: metric_name.to_string(),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            assert_eq!(response.metric_values.len(), 1);
+            let metric = response.metric_values.first().unwrap();
+            assert_eq!(metric.metric_name, metric_name);
+            assert_eq!(metric.metric_value, expected);
+        }
+
+        {
+            // unknown metric
+            let mut object_ref = object_ref.clone();
+            object_ref
+                .scaler_metadata
+                .insert("metrics".to_string(), "jobs,idleJobs".to_string());
+
+            let status = client
+                .get_metric_spec(Request::new(object_ref))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fractional_target() -> Result<()> {
+    let (server, client) = setup().await?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let scaler_metadata = HashMap::from([
+            ("queue".to_string(), "large".to_string()),
+            ("metrics".to_string(), "jobs,busyAgents".to_string()),
+            ("targetWaitingJobs".to_string(), "2.5".to_string()),
+            ("targetBusyAgents".to_string(), "3".to_string()),
+        ]);
+        let object_ref = ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        };
+
+        let response = client
+            .get_metric_spec(Request::new(object_ref.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.metric_specs.len(), 2);
+        // fractional targets are reported in milli-units, integer targets as they are
+        assert_eq!(response.metric_specs[0].target_size, 2500);
+        assert_eq!(response.metric_specs[1].target_size, 3);
+
+        for (metric_name, expected) in [
+            ("buildkite-large", 5000),
+            ("buildkite-large-busy-agents", 1),
+        ] {
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref.clone()),
+                metric_name: metric_name.to_string(),
+            });
+
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            let metric = response.metric_values.first().unwrap();
+            assert_eq!(metric.metric_value, expected);
+        }
+
+        for target in ["0", "-1", "0.0001", "one"] {
+            let mut object_ref = object_ref.clone();
+            object_ref
+                .scaler_metadata
+                .insert("targetWaitingJobs".to_string(), target.to_string());
+
+            let status = client
+                .get_metric_spec(Request::new(object_ref))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{target}");
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metric_value_limits() -> Result<()> {
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<DateTime<Utc>>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let clock = ManualClock(Arc::new(Mutex::new(
+        Utc.with_ymd_and_hms(2023, 9, 4, 10, 0, 0).unwrap(),
+    )));
+    let advance = |seconds: i64| {
+        *clock.0.lock().unwrap() += chrono::Duration::seconds(seconds);
+    };
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let (server, client) = serve(BuildkiteScaler::new(client).with_clock(clock.clone()))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let get_metric = |scaler_metadata: &[(&str, &str)]| {
+            let scaler_metadata = scaler_metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(ScaledObjectRef {
+                    namespace: "test".to_string(),
+                    name: "test".to_string(),
+                    scaler_metadata,
+                }),
+                metric_name: "buildkite-large".to_string(),
+            })
+        };
+
+        // large queue has 5 waiting jobs
+        let increase = [
+            ("queue", "large"),
+            ("maxMetricIncrease", "1"),
+            ("maxMetricIncreaseInterval", "10s"),
+        ];
+        for (scaler_metadata, seconds, expected) in [
+            (vec![("queue", "large"), ("maxMetricValue", "3")], 0, 3),
+            (vec![("queue", "large"), ("minMetricValue", "8")], 0, 8),
+            // the first value ramps up from the minimum
+            (increase.to_vec(), 0, 1),
+            // asking more often does not increase faster
+            (increase.to_vec(), 0, 1),
+            (increase.to_vec(), 5, 1),
+            (increase.to_vec(), 5, 2),
+            (increase.to_vec(), 25, 4),
+            (increase.to_vec(), 100, 5),
+            // forgotten when not polled, like after a restart
+            (increase.to_vec(), 3600, 1),
+        ] {
+            advance(seconds);
+            let response = client
+                .get_metrics(get_metric(&scaler_metadata))
+                .await
+                .unwrap()
+                .into_inner();
+            let metric = response.metric_values.first().unwrap();
+            assert_eq!(metric.metric_value, expected, "{:?}", scaler_metadata);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_runnable_schedule() -> Result<()> {
+    struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    // 2023-09-04 is a Monday
+    let now = Utc.with_ymd_and_hms(2023, 9, 4, 10, 0, 0).unwrap();
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let (server, client) = serve(BuildkiteScaler::new(client).with_clock(FixedClock(now)))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        for (schedule, active, value) in [
+            // default queue has no waiting jobs
+            ("* 9-17 * * Mon-Fri", true, 2),
+            ("* 9-17 * * Sat,Sun", false, 0),
+        ] {
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "default".to_string()),
+                ("minRunnable".to_string(), "2".to_string()),
+                ("minRunnableSchedule".to_string(), schedule.to_string()),
+            ]);
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+
+            let response = client
+                .is_active(Request::new(object_ref.clone()))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.result, active, "{}", schedule);
+
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: "buildkite-default".to_string(),
+            });
+            let response = client.get_metrics(request).await.unwrap().into_inner();
+            let metric = response.metric_values.first().unwrap();
+            assert_eq!(metric.metric_value, value, "{}", schedule);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_telemetry() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let telemetry = Telemetry::new();
+    let client = BuildkiteMetrics::builder(metrics.uri())
+        .token("test_token".to_string())
+        .telemetry(telemetry.clone())
+        .build()?;
+    let (server, client) = serve(BuildkiteScaler::new(client).with_telemetry(telemetry.clone()))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let object_ref = ScaledObjectRef {
+            namespace: "ci".to_string(),
+            name: "agents".to_string(),
+            scaler_metadata: HashMap::from([("queue".to_string(), "large".to_string())]),
+        };
+
+        client
+            .is_active(Request::new(object_ref.clone()))
+            .await
+            .unwrap();
+        client
+            .get_metrics(Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref.clone()),
+                metric_name: "buildkite-large".to_string(),
+            }))
+            .await
+            .unwrap();
+        client
+            .get_metrics(Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: "buildkite-small".to_string(),
+            }))
+            .await
+            .unwrap_err();
+
+        let output = telemetry.encode();
+        for line in [
+            r#"buildkite_queue_jobs{organization="test",queue="large",state="waiting"} 5"#,
+            r#"buildkite_queue_agents{organization="test",queue="small",state="busy"} 1"#,
+            r#"buildkite_api_requests_total{status="200"} 2"#,
+            r#"buildkite_api_request_duration_seconds_count{status="200"} 2"#,
+            r#"keda_scaler_requests_total{code="Ok",method="is_active",name="agents",namespace="ci"} 1"#,
+            r#"keda_scaler_requests_total{code="Ok",method="get_metrics",name="agents",namespace="ci"} 1"#,
+            r#"keda_scaler_requests_total{code="InvalidArgument",method="get_metrics",name="agents",namespace="ci"} 1"#,
+            r#"keda_scaler_metric_value{metric="buildkite-large",name="agents",namespace="ci"} 5"#,
+            r#"keda_scaler_active{name="agents",namespace="ci"} 1"#,
+        ] {
+            assert!(output.contains(line), "missing {}", line);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_metadata() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let client = BuildkiteMetrics::new(metrics.uri(), Some("test_token".to_string()));
+    let (server, client) = serve(BuildkiteScaler::new(client).with_strict_metadata(true))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        let scaler_metadata = HashMap::from([
+            ("queue".to_string(), "large".to_string()),
+            ("scalerAddress".to_string(), "localhost:9090".to_string()),
+            ("targetWaitngJobs".to_string(), "2".to_string()),
+            ("metricMode".to_string(), "fast".to_string()),
+        ]);
+        let request = Request::new(ScaledObjectRef {
+            namespace: "test".to_string(),
+            name: "test".to_string(),
+            scaler_metadata,
+        });
+
+        let status = client.get_metric_spec(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(status.message().contains("targetWaitngJobs"));
+        assert!(status.message().contains("metricMode"));
+
+        // every problem is listed in a google.rpc.BadRequest detail
+        let bad_request = status.get_details_bad_request().unwrap();
+        let fields = bad_request
+            .field_violations
+            .iter()
+            .map(|violation| violation.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, ["targetWaitngJobs", "metricMode"]);
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_organizations() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let registry = MetricsRegistry::new()
+        .with_organization(
+            "acme",
+            Arc::new(BuildkiteMetrics::new(
+                metrics.uri(),
+                Some("acme".to_string()),
+            )),
+        )
+        .with_organization(
+            "globex",
+            Arc::new(BuildkiteMetrics::new(
+                metrics.uri(),
+                Some("globex".to_string()),
+            )),
+        );
+    let (server, client) = serve(BuildkiteScaler::from_registry(registry))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        {
+            // configured organization
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("organization".to_string(), "acme".to_string()),
+            ]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let response = client.is_active(request).await.unwrap().into_inner();
+            assert!(response.result);
+        }
+
+        {
+            // unknown organization
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("organization".to_string(), "initech".to_string()),
+            ]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let status = client.is_active(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert!(status.message().contains("acme, globex"));
+        }
+
+        {
+            // no default organization
+            let scaler_metadata = HashMap::from([("queue".to_string(), "large".to_string())]);
+            let object_ref = ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            };
+            let request = Request::new(GetMetricsRequest {
+                scaled_object_ref: Some(object_ref),
+                metric_name: "buildkite-large".to_string(),
+            });
+
+            let status = client.get_metrics(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metadata_token() -> Result<()> {
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    // only accepts the team token
+    let team_metrics = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .and(header("Authorization", "Token team-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .mount(&team_metrics)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&team_metrics)
+        .await;
+
+    let created = Arc::new(AtomicUsize::new(0));
+    let token_providers = {
+        let created = created.clone();
+        let uri = team_metrics.uri();
+        TokenProviders::new("agentToken", move |token| {
+            created.fetch_add(1, Ordering::SeqCst);
+            let client: Arc<dyn MetricsProvider> =
+                Arc::new(BuildkiteMetrics::new(uri.clone(), Some(token)));
+            Ok(client)
+        })
+    };
+
+    let registry = MetricsRegistry::new()
+        .with_default(Arc::new(BuildkiteMetrics::new(
+            metrics.uri(),
+            Some("test_token".to_string()),
+        )))
+        .with_token_providers(token_providers);
+    let (server, client) = serve(BuildkiteScaler::from_registry(registry))?;
+
+    let test = async {
+        let mut client = client.await;
+
+        for _ in 0..2 {
+            // token from the metadata
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("agentToken".to_string(), "team-token".to_string()),
+            ]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let response = client.is_active(request).await.unwrap().into_inner();
+            assert!(response.result);
+        }
+
+        // the client for the token is reused
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        for _ in 0..2 {
+            // token is rejected by buildkite
+            let scaler_metadata = HashMap::from([
+                ("queue".to_string(), "large".to_string()),
+                ("agentToken".to_string(), "other-token".to_string()),
+            ]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let status = client.is_active(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+            assert!(!status.message().contains("other-token"));
+        }
+
+        // the client of the rejected token is not kept
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+
+        {
+            // no token uses the default client
+            let scaler_metadata = HashMap::from([("queue".to_string(), "large".to_string())]);
+            let request = Request::new(ScaledObjectRef {
+                namespace: "test".to_string(),
+                name: "test".to_string(),
+                scaler_metadata,
+            });
+
+            let response = client.is_active(request).await.unwrap().into_inner();
+            assert!(response.result);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metadata_token_eviction() -> Result<()> {
+    let metrics = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .and(header("Authorization", "Token team-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .mount(&metrics)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&metrics)
+        .await;
+
+    let uri = metrics.uri();
+    let token_providers = TokenProviders::new("agentToken", move |token| {
+        let client: Arc<dyn MetricsProvider> =
+            Arc::new(BuildkiteMetrics::new(uri.clone(), Some(token)));
+        Ok(client)
+    })
+    .with_idle_timeout(Duration::from_millis(200));
+
+    let team = token_providers.get_or_create("team-token")?;
+    assert!(team.metrics().await.is_ok());
+    let rejected = token_providers.get_or_create("other-token")?;
+    assert_eq!(token_providers.len(), 2);
+
+    // rejected tokens are dropped
+    assert!(matches!(
+        rejected.metrics().await,
+        Err(AgentApiError::Unauthorized { .. })
+    ));
+    assert_eq!(token_providers.len(), 1);
+
+    // idle tokens are dropped
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    token_providers.get_or_create("new-token")?;
+    assert_eq!(token_providers.len(), 1);
+
+    Ok(())
+}
+
+async fn mock_metrics(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .mount(server)
+        .await;
+}
+
+async fn setup() -> Result<(
+    impl Future<Output = ()>,
+    impl Future<Output = ExternalScalerClient<Channel>>,
+)> {
+    let auth_token = "test_token".to_string();
+
+    let metrics = MockServer::start().await;
+    mock_metrics(&metrics).await;
+
+    let client = BuildkiteMetrics::new(metrics.uri(), Some(auth_token));
+    let scaler = BuildkiteScaler::new(client);
+    serve(scaler)
+}
+
+fn serve(
+    scaler: BuildkiteScaler,
+) -> Result<(
+    impl Future<Output = ()>,
+    impl Future<Output = ExternalScalerClient<Channel>>,
+)> {
+    let mut rng = rand::thread_rng();
+    let port = rng.gen_range(9_000..10_000);
+    let address: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
+
+    let server = async move {
+        let result = Server::builder()
+            .add_service(scaler.into_service())
+            .serve(address)
+            .await;
+        assert!(result.is_ok());
     };
 
     let client = async move {
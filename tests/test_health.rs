@@ -0,0 +1,113 @@
+use std::{net::SocketAddr, time::Duration};
+
+use buildkite_keda_scaler::{AgentApiError, HealthState, Readiness, Shutdown};
+use color_eyre::Result;
+use rand::Rng;
+use tonic::transport::{Channel, Server};
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+
+#[tokio::test]
+async fn test_health_state() {
+    let health = HealthState::new();
+    let window = Duration::from_millis(200);
+
+    // no source configured, e.g. only tokens from metadata
+    assert!(health.check(window).is_ok());
+
+    let reporter = health.reporter("acme");
+    let problems = health.check(window).unwrap_err();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].starts_with("acme: no successful fetch"));
+
+    reporter.record_success();
+    assert!(health.check(window).is_ok());
+
+    // a transient error keeps the source ready within the window
+    reporter.record_error(&AgentApiError::NotFetched);
+    assert!(health.check(window).is_ok());
+
+    tokio::time::sleep(window * 2).await;
+    let problems = health.check(window).unwrap_err();
+    assert!(problems[0].contains("not been fetched"));
+
+    reporter.record_success();
+    reporter.record_error(&AgentApiError::Unauthorized {
+        status: reqwest::StatusCode::UNAUTHORIZED,
+        message: None,
+    });
+    let problems = health.check(window).unwrap_err();
+    assert!(problems[0].starts_with("acme: agent token rejected"));
+}
+
+#[tokio::test]
+async fn test_readiness_shutdown() {
+    let health = HealthState::new();
+    health.reporter("default").record_success();
+
+    let shutdown = Shutdown::new();
+    let readiness = Readiness::new(health, Duration::from_secs(60)).with_shutdown(shutdown.clone());
+    assert!(readiness.check().await.is_ok());
+
+    shutdown.trigger();
+    let problems = readiness.check().await.unwrap_err();
+    assert_eq!(problems, ["shutting down"]);
+}
+
+#[tokio::test]
+async fn test_grpc_health() -> Result<()> {
+    let health = HealthState::new();
+    let reporter = health.reporter("default");
+    let readiness = Readiness::new(health, Duration::from_secs(60));
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(readiness.report_grpc_health(health_reporter, Duration::from_millis(100)));
+
+    let port = rand::thread_rng().gen_range(11_000..12_000);
+    let address: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+    let server = Server::builder().add_service(health_service).serve(address);
+
+    let test = async {
+        // give time to the server to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        for service in ["", "externalscaler.ExternalScaler"] {
+            let response = client
+                .check(HealthCheckRequest {
+                    service: service.to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status(), ServingStatus::NotServing);
+        }
+
+        reporter.record_success();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        for service in ["", "externalscaler.ExternalScaler"] {
+            let response = client
+                .check(HealthCheckRequest {
+                    service: service.to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status(), ServingStatus::Serving);
+        }
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
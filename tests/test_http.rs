@@ -0,0 +1,137 @@
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use buildkite_keda_scaler::{
+    BuildkiteMetrics, HealthState, HttpServer, MetricsRegistry, Readiness, Telemetry,
+};
+use color_eyre::Result;
+use rand::Rng;
+use serde_json::json;
+use wiremock::{
+    matchers::{header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+mod common;
+
+use common::metrics_body;
+
+#[tokio::test]
+async fn test_serve_metrics() -> Result<()> {
+    let telemetry = Telemetry::new();
+    telemetry.observe_api_request("503", Duration::from_millis(20));
+    telemetry.observe_active("ci", "agents", false);
+
+    let (server, address) = serve(HttpServer::new().with_telemetry(telemetry))?;
+
+    let test = async {
+        // give time to the server to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = response.text().await.unwrap();
+        assert!(body.contains(r#"buildkite_api_requests_total{status="503"} 1"#));
+        assert!(body.contains(r#"keda_scaler_active{name="agents",namespace="ci"} 0"#));
+
+        let response = reqwest::get(format!("http://{}/other", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    };
+
+    tokio::select! {
+        _ = server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_probes() -> Result<()> {
+    let buildkite = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .and(header("Authorization", "Token valid"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .mount(&buildkite)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "message": "Invalid token",
+        })))
+        .mount(&buildkite)
+        .await;
+
+    let health = HealthState::new();
+    let valid = BuildkiteMetrics::builder(buildkite.uri())
+        .token("valid".to_string())
+        .health(health.reporter("valid"))
+        .build()?;
+    let registry = MetricsRegistry::new().with_default(std::sync::Arc::new(valid));
+    let readiness = Readiness::new(health, Duration::from_secs(60)).with_probes(&registry);
+    let (ready_server, ready_address) = serve(HttpServer::new().with_readiness(readiness))?;
+
+    let health = HealthState::new();
+    let invalid = BuildkiteMetrics::builder(buildkite.uri())
+        .token("invalid".to_string())
+        .health(health.reporter("invalid"))
+        .build()?;
+    let registry =
+        MetricsRegistry::new().with_organization("invalid", std::sync::Arc::new(invalid));
+    let readiness = Readiness::new(health, Duration::from_secs(60)).with_probes(&registry);
+    let (unready_server, unready_address) = serve(HttpServer::new().with_readiness(readiness))?;
+
+    let test = async {
+        // give time to the servers to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        for address in [ready_address, unready_address] {
+            let response = reqwest::get(format!("http://{}/healthz", address))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }
+
+        // readiness fetches metrics on demand
+        let response = reqwest::get(format!("http://{}/readyz", ready_address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = reqwest::get(format!("http://{}/readyz", unready_address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("invalid: agent token rejected"), "{}", body);
+        assert!(body.contains("Invalid token"), "{}", body);
+    };
+
+    tokio::select! {
+        _ = ready_server => panic!("server exited"),
+        _ = unready_server => panic!("server exited"),
+        _ = test => (),
+    }
+
+    Ok(())
+}
+
+fn serve(server: HttpServer) -> Result<(impl Future<Output = ()>, SocketAddr)> {
+    let port = rand::thread_rng().gen_range(10_000..11_000);
+    let address: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+
+    let server = async move {
+        let result = server.serve(address).await;
+        assert!(result.is_ok());
+    };
+
+    Ok((server, address))
+}
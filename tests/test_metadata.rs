@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use chrono::{TimeZone, Utc};
+
+use buildkite_keda_scaler::{
+    externalscaler::{MetricKind, MetricMode},
+    JobState, MetricTarget, ParseOptions, ReportedValue, ScalerConfig,
+};
+
+fn metadata(entries: &[(&str, &str)]) -> HashMap<String, String> {
+    entries
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_parse_defaults() {
+    let config =
+        ScalerConfig::parse(&metadata(&[("queue", "default")]), &ParseOptions::default()).unwrap();
+
+    assert_eq!(config.queue.to_string(), "default");
+    assert_eq!(config.activation_waiting_jobs, 0);
+    assert_eq!(config.job_states, [JobState::Scheduled, JobState::Waiting]);
+    assert_eq!(config.metric_mode, MetricMode::Runnable);
+    assert_eq!(
+        config
+            .metrics
+            .iter()
+            .map(|metric| metric.kind)
+            .collect::<Vec<_>>(),
+        [MetricKind::Jobs]
+    );
+    assert_eq!(
+        config.metric(MetricKind::Jobs).unwrap().target,
+        MetricTarget::from_units(1)
+    );
+    assert!(config.organization.is_none());
+}
+
+#[test]
+fn test_parse_reports_all_errors() {
+    let metadata = metadata(&[
+        ("targetWaitingJobs", "0"),
+        ("jobStates", "waiting,done"),
+        ("metricMode", "fast"),
+        ("targetWaitngJobs", "2"),
+    ]);
+
+    // unknown keys are ignored unless strict
+    let err = ScalerConfig::parse(&metadata, &ParseOptions::default()).unwrap_err();
+    let fields = err
+        .violations()
+        .iter()
+        .map(|violation| violation.field.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        fields,
+        ["queue", "jobStates", "metricMode", "targetWaitingJobs"]
+    );
+
+    let options = ParseOptions {
+        strict: true,
+        extra_keys: Vec::new(),
+    };
+    let err = ScalerConfig::parse(&metadata, &options).unwrap_err();
+    assert_eq!(err.violations().len(), 5);
+    assert_eq!(err.violations()[0].field, "targetWaitngJobs");
+
+    let message = err.to_string();
+    assert!(message.contains("targetWaitngJobs: unknown metadata key"));
+    assert!(message.contains("queue not specified"));
+    assert!(message.contains("metricMode fast"));
+}
+
+#[test]
+fn test_parse_activation_waiting_jobs() {
+    let config = ScalerConfig::parse(
+        &metadata(&[("queue", "default"), ("activationWaitingJobs", "3")]),
+        &ParseOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(config.activation_waiting_jobs, 3);
+
+    for value in ["-1", "some"] {
+        let err = ScalerConfig::parse(
+            &metadata(&[("queue", "default"), ("activationWaitingJobs", value)]),
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.violations()[0].field, "activationWaitingJobs");
+        assert_eq!(
+            err.violations()[0].description,
+            "activationWaitingJobs must be an integer of at least 0"
+        );
+    }
+}
+
+#[test]
+fn test_parse_strict() {
+    let options = ParseOptions {
+        strict: true,
+        extra_keys: vec!["agentToken".to_string()],
+    };
+
+    let metadata = metadata(&[
+        ("queue", "default"),
+        ("scalerAddress", "buildkite-keda-scaler:9090"),
+        ("enableTLS", "false"),
+        ("agentToken", "token"),
+    ]);
+    assert!(ScalerConfig::parse(&metadata, &options).is_ok());
+}
+
+#[test]
+fn test_limit_metric_value() {
+    let config = ScalerConfig::parse(
+        &metadata(&[
+            ("queue", "default"),
+            ("minMetricValue", "1"),
+            ("maxMetricValue", "20"),
+            ("maxMetricIncrease", "5"),
+        ]),
+        &ParseOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(config.limit_metric_value(15, None, 0), 15);
+    assert_eq!(config.limit_metric_value(100, None, 0), 20);
+    assert_eq!(config.limit_metric_value(0, None, 0), 1);
+
+    assert_eq!(config.limit_metric_value(100, Some(9), 0), 9);
+    assert_eq!(config.limit_metric_value(100, Some(23), 0), 20);
+    // decreases are not limited
+    assert_eq!(config.limit_metric_value(2, Some(23), 0), 2);
+
+    // 5 per 30s by default
+    let now = Utc.with_ymd_and_hms(2023, 9, 4, 10, 0, 0).unwrap();
+    let previous = ReportedValue {
+        value: 4,
+        at: now - chrono::Duration::seconds(30),
+    };
+    assert_eq!(config.increase_limit(&previous, now), Some(9));
+    let previous = ReportedValue { value: 4, at: now };
+    assert_eq!(config.increase_limit(&previous, now), Some(4));
+    let previous = ReportedValue {
+        value: 4,
+        at: now - chrono::Duration::seconds(75),
+    };
+    assert_eq!(config.increase_limit(&previous, now), Some(16));
+
+    // without a previous value, the increase starts from the minimum
+    let initial = config.initial_reported_value(0, now);
+    assert_eq!(config.increase_limit(&initial, now), Some(6));
+    let initial = config.initial_reported_value(3, now);
+    assert_eq!(config.increase_limit(&initial, now), Some(8));
+
+    for (key, value) in [
+        ("minMetricValue", "-1"),
+        ("maxMetricValue", "many"),
+        ("maxMetricIncrease", "0"),
+        ("maxMetricIncreaseInterval", "0s"),
+        ("maxMetricIncreaseInterval", "often"),
+    ] {
+        let err = ScalerConfig::parse(
+            &metadata(&[("queue", "default"), (key, value)]),
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.violations()[0].field, key);
+    }
+
+    let err = ScalerConfig::parse(
+        &metadata(&[
+            ("queue", "default"),
+            ("minMetricValue", "10"),
+            ("maxMetricValue", "5"),
+        ]),
+        &ParseOptions::default(),
+    )
+    .unwrap_err();
+    assert_eq!(err.violations()[0].field, "minMetricValue");
+}
+
+#[test]
+fn test_parse_min_runnable() {
+    let config = ScalerConfig::parse(
+        &metadata(&[
+            ("queue", "default"),
+            ("minRunnable", "2"),
+            ("minRunnableSchedule", "* 9-17 * * Mon-Fri"),
+            ("minRunnableTimezone", "Europe/Rome"),
+        ]),
+        &ParseOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(config.min_runnable.unwrap().value(), 2);
+
+    for (entries, field) in [
+        (vec![("minRunnable", "2")], "minRunnable"),
+        (
+            vec![("minRunnableSchedule", "every day")],
+            "minRunnableSchedule",
+        ),
+        (
+            vec![
+                ("minRunnableSchedule", "* 9-17 * * *"),
+                ("minRunnableTimezone", "Mars/Olympus"),
+            ],
+            "minRunnableTimezone",
+        ),
+    ] {
+        let mut metadata = metadata(&entries);
+        metadata.insert("queue".to_string(), "default".to_string());
+        let err = ScalerConfig::parse(&metadata, &ParseOptions::default()).unwrap_err();
+        assert_eq!(err.violations()[0].field, field);
+    }
+}
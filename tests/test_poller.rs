@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use buildkite_keda_scaler::{BuildkiteMetrics, MetricsPoller, MetricsProvider};
+use color_eyre::Result;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+mod common;
+
+use common::metrics_body;
+
+#[tokio::test]
+async fn test_poller_serves_latest_snapshot() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::new(server.uri(), None);
+    let poller = MetricsPoller::spawn(
+        client,
+        Duration::from_millis(50),
+        Duration::from_millis(300),
+    );
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let snapshot = poller.snapshot().unwrap();
+    assert_eq!(snapshot.metrics.organization.slug, "test");
+    let metrics = poller.metrics().await?;
+    assert_eq!(metrics.organization.slug, "test");
+
+    // upstream keeps failing, so the snapshot becomes too old to use
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(poller.metrics().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_poller_without_snapshot() -> Result<()> {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let client = BuildkiteMetrics::new(server.uri(), None);
+    let poller = MetricsPoller::spawn(client, Duration::from_millis(50), Duration::from_secs(60));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(poller.snapshot().is_none());
+    assert!(poller.metrics().await.is_err());
+
+    Ok(())
+}
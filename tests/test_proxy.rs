@@ -0,0 +1,69 @@
+use buildkite_keda_scaler::{BuildkiteMetrics, RetryPolicy};
+use color_eyre::Result;
+use wiremock::{
+    matchers::{header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+mod common;
+
+use common::metrics_body;
+
+// Runs in its own test binary since it changes the process environment.
+#[tokio::test]
+async fn test_no_proxy_applies_to_environment_proxies() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .and(header("user-agent", "custom/1.0"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .mount(&server)
+        .await;
+
+    // nothing listens on the discard port
+    std::env::set_var("HTTP_PROXY", "http://127.0.0.1:9");
+    std::env::remove_var("http_proxy");
+    for name in [
+        "NO_PROXY",
+        "no_proxy",
+        "ALL_PROXY",
+        "all_proxy",
+        "REQUEST_METHOD",
+    ] {
+        std::env::remove_var(name);
+    }
+
+    let builder = BuildkiteMetrics::builder(server.uri()).user_agent("custom/1.0");
+
+    let client = builder.clone().build()?;
+    assert!(client.get().await.is_err());
+
+    let client = builder.no_proxy("127.0.0.1").build()?;
+    let metrics = client.get().await?;
+    assert_eq!(metrics.organization.slug, "test");
+
+    // ALL_PROXY is used for hosts not excluded
+    let proxy = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v3/metrics"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(metrics_body()))
+        .expect(1)
+        .mount(&proxy)
+        .await;
+    std::env::remove_var("HTTP_PROXY");
+    std::env::set_var("ALL_PROXY", proxy.uri());
+
+    let builder = BuildkiteMetrics::builder("http://buildkite.invalid").retry_policy(RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::default()
+    });
+
+    let client = builder.clone().no_proxy("127.0.0.1").build()?;
+    let metrics = client.get().await?;
+    assert_eq!(metrics.organization.slug, "test");
+
+    let client = builder.no_proxy("buildkite.invalid").build()?;
+    assert!(client.get().await.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use buildkite_keda_scaler::QueueSelector;
+
+#[test]
+fn test_parse() {
+    assert!(QueueSelector::parse("").is_none());
+    assert!(QueueSelector::parse(" , ").is_none());
+
+    let selector = QueueSelector::parse("docker, default,docker").unwrap();
+    assert_eq!(selector.to_string(), "default,docker");
+    assert!(selector.as_single_queue().is_none());
+
+    let selector = QueueSelector::parse("default").unwrap();
+    assert_eq!(selector.as_single_queue(), Some("default"));
+}
+
+#[test]
+fn test_matches() {
+    let selector = QueueSelector::parse("default,linux-*").unwrap();
+    assert!(selector.matches("default"));
+    assert!(selector.matches("linux-"));
+    assert!(selector.matches("linux-large"));
+    assert!(!selector.matches("macos-large"));
+    assert!(!selector.matches("default-2"));
+
+    let selector = QueueSelector::parse("*-large-?").unwrap();
+    assert!(selector.matches("linux-large-1"));
+    assert!(selector.matches("linux-large-large-1"));
+    assert!(!selector.matches("linux-large-12"));
+    assert!(!selector.matches("linux-small-1"));
+}
+
+#[test]
+fn test_name() {
+    let selector = QueueSelector::parse("default").unwrap();
+    assert_eq!(selector.name(), "default");
+
+    // the order of the queues doesn't change the name
+    let first = QueueSelector::parse("default,linux-*").unwrap();
+    let second = QueueSelector::parse("linux-*,default").unwrap();
+    assert_eq!(first.name(), second.name());
+    assert!(first.name().starts_with("default-linux-x-"));
+
+    let other = QueueSelector::parse("default,linux-?").unwrap();
+    assert_ne!(first.name(), other.name());
+}
@@ -0,0 +1,66 @@
+use std::{net::SocketAddr, time::Duration};
+
+use buildkite_keda_scaler::reflection_service;
+use color_eyre::Result;
+use rand::Rng;
+use tonic::transport::{Channel, Server};
+use tonic_reflection::pb::{
+    server_reflection_client::ServerReflectionClient, server_reflection_request::MessageRequest,
+    server_reflection_response::MessageResponse, ServerReflectionRequest,
+};
+
+#[tokio::test]
+async fn test_reflection_lists_services() -> Result<()> {
+    let port = rand::thread_rng().gen_range(13_000..14_000);
+    let address: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+    let server = Server::builder()
+        .add_service(reflection_service())
+        .serve(address);
+
+    let test = async {
+        // give time to the server to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = ServerReflectionClient::new(channel);
+
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let mut responses = client
+            .server_reflection_info(tokio_stream::iter([request]))
+            .await
+            .unwrap()
+            .into_inner();
+        let response = responses.message().await.unwrap().unwrap();
+
+        let Some(MessageResponse::ListServicesResponse(list)) = response.message_response else {
+            panic!("unexpected response {:?}", response.message_response);
+        };
+        let mut services = list
+            .service
+            .into_iter()
+            .map(|service| service.name)
+            .collect::<Vec<_>>();
+        services.sort();
+        assert_eq!(
+            services,
+            [
+                "externalscaler.ExternalScaler",
+                "grpc.health.v1.Health",
+                "grpc.reflection.v1alpha.ServerReflection",
+            ]
+        );
+    };
+
+    tokio::select! {
+        _ = server => {},
+        _ = test => {},
+    }
+
+    Ok(())
+}
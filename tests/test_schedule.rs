@@ -0,0 +1,96 @@
+use buildkite_keda_scaler::RunnableFloor;
+use chrono::{DateTime, TimeZone, Utc};
+
+fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+        .unwrap()
+}
+
+#[test]
+fn test_parse() {
+    assert!(RunnableFloor::parse(1, "", chrono_tz::UTC).is_err());
+    assert!(RunnableFloor::parse(1, "0 * 9-17 * * Mon-Fri", chrono_tz::UTC).is_err());
+    assert!(RunnableFloor::parse(1, "* 25 * * *", chrono_tz::UTC).is_err());
+    assert!(RunnableFloor::parse(1, "* * * * 8", chrono_tz::UTC).is_err());
+    assert!(RunnableFloor::parse(1, "* * * * 5-1", chrono_tz::UTC).is_err());
+    assert!(RunnableFloor::parse(1, "* 9-17 * * Mon-Fri; * 10-12 * * Sat", chrono_tz::UTC).is_ok());
+}
+
+#[test]
+fn test_working_hours() {
+    let floor = RunnableFloor::parse(3, "* 9-17 * * Mon-Fri", chrono_tz::Europe::London).unwrap();
+
+    // 2023-09-04 is a Monday, London is at UTC+1
+    assert_eq!(floor.at(utc(2023, 9, 4, 8, 0)), 3);
+    assert_eq!(floor.at(utc(2023, 9, 4, 16, 59)), 3);
+    assert_eq!(floor.at(utc(2023, 9, 4, 7, 59)), 0);
+    assert_eq!(floor.at(utc(2023, 9, 4, 17, 0)), 0);
+
+    // seconds are ignored
+    let now = Utc.with_ymd_and_hms(2023, 9, 4, 8, 0, 42).unwrap();
+    assert!(floor.is_active_at(now));
+
+    // saturday
+    assert_eq!(floor.at(utc(2023, 9, 2, 10, 0)), 0);
+}
+
+#[test]
+fn test_multiple_windows() {
+    let floor =
+        RunnableFloor::parse(1, "* 9-17 * * Mon-Fri; 0-29 10 * * Sat", chrono_tz::UTC).unwrap();
+
+    assert!(floor.is_active_at(utc(2023, 9, 4, 9, 0)));
+    assert!(floor.is_active_at(utc(2023, 9, 2, 10, 29)));
+    assert!(!floor.is_active_at(utc(2023, 9, 2, 10, 30)));
+    assert!(!floor.is_active_at(utc(2023, 9, 3, 10, 0)));
+}
+
+#[test]
+fn test_numeric_days_of_week() {
+    // 2023-09-03 is a Sunday
+    let sunday = utc(2023, 9, 3, 10, 0);
+    let monday = utc(2023, 9, 4, 10, 0);
+    let friday = utc(2023, 9, 8, 10, 0);
+    let saturday = utc(2023, 9, 9, 10, 0);
+
+    let weekdays = RunnableFloor::parse(1, "* 9-17 * * 1-5", chrono_tz::UTC).unwrap();
+    assert!(!weekdays.is_active_at(sunday));
+    assert!(weekdays.is_active_at(monday));
+    assert!(weekdays.is_active_at(friday));
+    assert!(!weekdays.is_active_at(saturday));
+
+    for expression in ["* * * * 0", "* * * * 7"] {
+        let floor = RunnableFloor::parse(1, expression, chrono_tz::UTC).unwrap();
+        assert!(floor.is_active_at(sunday));
+        assert!(!floor.is_active_at(monday));
+    }
+
+    let weekend = RunnableFloor::parse(1, "* * * * 6-7", chrono_tz::UTC).unwrap();
+    assert!(weekend.is_active_at(saturday));
+    assert!(weekend.is_active_at(sunday));
+    assert!(!weekend.is_active_at(friday));
+
+    let every_other_day = RunnableFloor::parse(1, "* * * * */2", chrono_tz::UTC).unwrap();
+    assert!(every_other_day.is_active_at(sunday));
+    assert!(!every_other_day.is_active_at(monday));
+    assert!(every_other_day.is_active_at(saturday));
+}
+
+#[test]
+fn test_day_of_month_or_day_of_week() {
+    // 2023-09-01 is a Friday
+    let first = utc(2023, 9, 1, 10, 0);
+    let monday = utc(2023, 9, 4, 10, 0);
+    let tuesday = utc(2023, 9, 5, 10, 0);
+
+    let floor = RunnableFloor::parse(1, "* * 1 * Mon", chrono_tz::UTC).unwrap();
+    assert!(floor.is_active_at(first));
+    assert!(floor.is_active_at(monday));
+    assert!(!floor.is_active_at(tuesday));
+
+    // a day field starting with `*` is unrestricted, both must match
+    let floor = RunnableFloor::parse(1, "* * */2 * Mon", chrono_tz::UTC).unwrap();
+    assert!(!floor.is_active_at(first));
+    assert!(!floor.is_active_at(monday));
+    assert!(floor.is_active_at(utc(2023, 9, 11, 10, 0)));
+}
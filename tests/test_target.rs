@@ -0,0 +1,42 @@
+use buildkite_keda_scaler::MetricTarget;
+
+#[test]
+fn test_parse() {
+    for value in [
+        "", "0", "0.0", "-1", "-0.5", "1.2345", ".5", "1e3", "abc", "1.5.0",
+    ] {
+        assert!(MetricTarget::parse(value).is_none(), "{value}");
+    }
+
+    let target = MetricTarget::parse("3").unwrap();
+    assert!(target.is_integer());
+    assert_eq!(target, MetricTarget::from_units(3));
+    assert_eq!(target.to_string(), "3");
+
+    let target = MetricTarget::parse("2.5").unwrap();
+    assert!(!target.is_integer());
+    assert_eq!(target.to_string(), "2.5");
+
+    let target = MetricTarget::parse("0.125").unwrap();
+    assert_eq!(target.to_string(), "0.125");
+
+    assert_eq!(
+        MetricTarget::parse("2.000"),
+        Some(MetricTarget::from_units(2))
+    );
+}
+
+#[test]
+fn test_scale() {
+    let target = MetricTarget::from_units(4);
+    assert_eq!(target.target_size(), 4);
+    assert_eq!(target.scale_value(10), 10);
+
+    let target = MetricTarget::parse("2.5").unwrap();
+    assert_eq!(target.target_size(), 2500);
+    assert_eq!(target.scale_value(10), 10_000);
+
+    let target = MetricTarget::parse("0.5").unwrap();
+    assert_eq!(target.target_size(), 500);
+    assert_eq!(target.scale_value(0), 0);
+}
@@ -0,0 +1,175 @@
+use std::{net::SocketAddr, time::Duration};
+
+use buildkite_keda_scaler::{serve_with_tls_reload, Shutdown, TlsFiles};
+use color_eyre::Result;
+use rand::Rng;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use tokio::net::TcpListener;
+use tonic::transport::{self, Channel, ClientTlsConfig, Identity};
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+
+/// A CA and the server and client certificates it signs, in PEM.
+struct Pki {
+    ca: String,
+    server: (String, String),
+    client: (String, String),
+}
+
+impl Pki {
+    fn generate() -> Self {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+
+        let sign = |name: &str| {
+            let cert =
+                Certificate::from_params(CertificateParams::new(vec![name.to_string()])).unwrap();
+            (
+                cert.serialize_pem_with_signer(&ca).unwrap(),
+                cert.serialize_private_key_pem(),
+            )
+        };
+
+        Self {
+            server: sign("localhost"),
+            client: sign("client"),
+            ca: ca.serialize_pem().unwrap(),
+        }
+    }
+
+    /// Writes the server files, overwriting the previous ones.
+    fn write(&self, files: &TlsFiles) {
+        std::fs::write(&files.cert, &self.server.0).unwrap();
+        std::fs::write(&files.key, &self.server.1).unwrap();
+        if let Some(client_ca) = &files.client_ca {
+            std::fs::write(client_ca, &self.ca).unwrap();
+        }
+    }
+}
+
+fn tls_files(mutual: bool) -> TlsFiles {
+    let dir = std::env::temp_dir().join(format!(
+        "buildkite-keda-scaler-tls-{}",
+        rand::thread_rng().gen::<u64>()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    TlsFiles {
+        cert: dir.join("tls.crt"),
+        key: dir.join("tls.key"),
+        client_ca: mutual.then(|| dir.join("ca.crt")),
+    }
+}
+
+async fn listen() -> Result<(TcpListener, SocketAddr)> {
+    let port = rand::thread_rng().gen_range(12_000..13_000);
+    let address: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+    Ok((TcpListener::bind(address).await?, address))
+}
+
+async fn serve(listener: TcpListener, files: TlsFiles, shutdown: Shutdown) -> Result<()> {
+    let (_, health_service) = tonic_health::server::health_reporter();
+    serve_with_tls_reload(
+        listener,
+        files,
+        Duration::from_millis(100),
+        shutdown,
+        |mut server| server.add_service(health_service.clone()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Calls the health service trusting `ca`, authenticating with `client` if set.
+async fn check(
+    address: SocketAddr,
+    ca: &str,
+    client: Option<&(String, String)>,
+) -> Result<ServingStatus> {
+    let mut tls = ClientTlsConfig::new()
+        .ca_certificate(transport::Certificate::from_pem(ca))
+        .domain_name("localhost");
+    if let Some((cert, key)) = client {
+        tls = tls.identity(Identity::from_pem(cert, key));
+    }
+
+    let channel = Channel::from_shared(format!("https://{}", address))?
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    let response = HealthClient::new(channel)
+        .check(HealthCheckRequest {
+            service: String::new(),
+        })
+        .await?;
+    Ok(response.into_inner().status())
+}
+
+#[tokio::test]
+async fn test_mutual_tls() -> Result<()> {
+    let pki = Pki::generate();
+    let files = tls_files(true);
+    pki.write(&files);
+
+    let (listener, address) = listen().await?;
+    let shutdown = Shutdown::new();
+    let server = serve(listener, files, shutdown.clone());
+
+    let test = async {
+        // give time to the server to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let status = check(address, &pki.ca, Some(&pki.client)).await.unwrap();
+        assert_eq!(status, ServingStatus::Serving);
+
+        // no client certificate
+        assert!(check(address, &pki.ca, None).await.is_err());
+
+        // client certificate signed by another CA
+        let other = Pki::generate();
+        assert!(check(address, &pki.ca, Some(&other.client)).await.is_err());
+
+        shutdown.trigger();
+    };
+
+    let (result, _) = tokio::join!(server, test);
+    result
+}
+
+#[tokio::test]
+async fn test_tls_reload() -> Result<()> {
+    let pki = Pki::generate();
+    let files = tls_files(false);
+    pki.write(&files);
+
+    let (listener, address) = listen().await?;
+    let shutdown = Shutdown::new();
+    let server = serve(listener, files.clone(), shutdown.clone());
+
+    let test = async {
+        // give time to the server to start
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let status = check(address, &pki.ca, None).await.unwrap();
+        assert_eq!(status, ServingStatus::Serving);
+
+        // an invalid key keeps the current certificate
+        std::fs::write(&files.key, "not a key").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(check(address, &pki.ca, None).await.is_ok());
+
+        let rotated = Pki::generate();
+        rotated.write(&files);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let status = check(address, &rotated.ca, None).await.unwrap();
+        assert_eq!(status, ServingStatus::Serving);
+        assert!(check(address, &pki.ca, None).await.is_err());
+
+        shutdown.trigger();
+    };
+
+    let (result, _) = tokio::join!(server, test);
+    result
+}